@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::hint::black_box;
+
+use pk2::{BlowFish, Padding};
+
+const ENTRY_SIZE: usize = 128;
+const BLOCK_SIZE: usize = 20 * ENTRY_SIZE;
+const PK2_KEYS: &[u8] = &[0x32, 0xCE, 0xDD, 0x7C, 0xBC, 0xA8];
+
+// One 2560 byte entry block, decrypted per entry into a fresh array
+// and in place all at once.
+fn entry_block(c: &mut Criterion) {
+    let blowfish = BlowFish::new(PK2_KEYS).unwrap();
+    let plain: Vec<u8> = (0..BLOCK_SIZE).map(|i| i as u8).collect();
+    let encrypted = blowfish.encrypt_padded(&plain, Padding::None).unwrap();
+
+    let mut group = c.benchmark_group("entry_block");
+    group.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    group.bench_function("per_entry", |b| b.iter(|| {
+        for chunk in encrypted.chunks(ENTRY_SIZE) {
+            let mut entry = [0; ENTRY_SIZE];
+            entry.copy_from_slice(black_box(chunk));
+            blowfish.decrypt_in_place(&mut entry).unwrap();
+            black_box(entry);
+        }
+    }));
+    let mut buffer = encrypted.clone();
+    group.bench_function("in_place", |b| b.iter(|| {
+        buffer.copy_from_slice(&encrypted);
+        blowfish.decrypt_in_place(black_box(&mut buffer)).unwrap();
+    }));
+    group.finish();
+}
+
+fn key_setup(c: &mut Criterion) {
+    c.bench_function("key_setup", |b| b.iter(|| BlowFish::new(black_box(PK2_KEYS)).unwrap()));
+}
+
+criterion_group!(benches, entry_block, key_setup);
+criterion_main!(benches);
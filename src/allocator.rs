@@ -0,0 +1,215 @@
+/**
+ * A byte range of the archive, used both for the ranges entries point at
+ * and for the holes left between them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub fn new(offset: u64, size: u64) -> Self {
+        Self { offset, size }
+    }
+
+    /// Clamped to `u64::MAX`, a damaged entry can point anywhere.
+    pub fn end(&self) -> u64 {
+        self.offset.saturating_add(self.size)
+    }
+}
+
+/**
+ * Every byte range of the archive that no entry and no entry block references,
+ * sorted by offset and with neighbouring holes merged.
+ */
+#[derive(Clone, Debug)]
+pub struct FreeSpaceMap {
+    regions: Vec<Region>,
+    file_size: u64,
+}
+
+impl FreeSpaceMap {
+    /// Everything below `file_size` that isn't covered by one of `used` is free.
+    /// `used` may be unsorted and overlapping, ranges past the end are clamped.
+    pub fn from_used(mut used: Vec<Region>, file_size: u64) -> Self {
+        used.sort_by_key(|region| region.offset);
+
+        let mut regions = Vec::new();
+        let mut cursor = 0;
+        for region in used.iter().filter(|region| region.size > 0) {
+            if region.offset > cursor {
+                regions.push(Region::new(cursor, region.offset.min(file_size) - cursor));
+            }
+            cursor = cursor.max(region.end());
+            if cursor >= file_size {
+                break;
+            }
+        }
+        if cursor < file_size {
+            regions.push(Region::new(cursor, file_size - cursor));
+        }
+
+        Self { regions, file_size }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.regions.iter().map(|region| region.size).sum()
+    }
+
+    pub fn largest_region(&self) -> u64 {
+        self.regions.iter().map(|region| region.size).max().unwrap_or(0)
+    }
+
+    /// Adds `freed` to the holes, apart from whatever of it `live` still covers.
+    pub(crate) fn release(&mut self, freed: &[Region], live: &[Region]) {
+        let mut regions: Vec<Region> = self.regions.iter().chain(freed)
+            .filter(|region| region.size > 0 && region.offset < self.file_size)
+            .map(|region| Region::new(region.offset, region.end().min(self.file_size) - region.offset))
+            .collect();
+        regions.sort_by_key(|region| region.offset);
+
+        let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+        for region in regions {
+            match merged.last_mut() {
+                Some(last) if region.offset <= last.end() => last.size = last.end().max(region.end()) - last.offset,
+                _ => merged.push(region),
+            }
+        }
+        for used in live.iter().filter(|used| used.size > 0) {
+            merged = merged.into_iter().flat_map(|region| {
+                let before = Region::new(region.offset, used.offset.clamp(region.offset, region.end()) - region.offset);
+                let after_start = used.end().clamp(region.offset, region.end());
+                let after = Region::new(after_start, region.end() - after_start);
+                [before, after]
+            }).filter(|region| region.size > 0).collect();
+        }
+        self.regions = merged;
+    }
+
+    /// 0.0 when all the free space is a single hole (or there is none),
+    /// getting closer to 1.0 the more it is scattered across small holes.
+    pub fn fragmentation(&self) -> f64 {
+        let free = self.free_bytes();
+        if free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_region() as f64 / free as f64
+    }
+}
+
+/**
+ * Hands out space for new data and entry blocks, filling the holes
+ * of the free-space map before growing the archive at EOF.
+ */
+pub struct Allocator {
+    map: FreeSpaceMap,
+}
+
+impl Allocator {
+    pub fn new(map: FreeSpaceMap) -> Self {
+        Self { map }
+    }
+
+    /// Returns the offset `size` bytes can be written at.
+    pub fn allocate(&mut self, size: u64) -> u64 {
+        if size == 0 {
+            return self.map.file_size;
+        }
+
+        // first fit, we don't care much about where small files end up.
+        if let Some(index) = self.map.regions.iter().position(|region| region.size >= size) {
+            let region = &mut self.map.regions[index];
+            let offset = region.offset;
+            region.offset += size;
+            region.size -= size;
+            if region.size == 0 {
+                self.map.regions.remove(index);
+            }
+            return offset;
+        }
+
+        // a hole right before EOF is still worth starting in.
+        let offset = match self.map.regions.last() {
+            Some(region) if region.end() == self.map.file_size => {
+                self.map.regions.pop().unwrap().offset
+            },
+            _ => self.map.file_size,
+        };
+        self.map.file_size = offset + size;
+        offset
+    }
+
+    /// The map with everything allocated so far taken out of it.
+    pub fn into_map(self) -> FreeSpaceMap {
+        self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Allocator, FreeSpaceMap, Region};
+
+    #[test]
+    fn test_free_space_map() {
+        let used = vec![
+            Region::new(0, 256),
+            Region::new(1000, 100),
+            Region::new(256, 500),
+            Region::new(1050, 100),
+        ];
+        let map = FreeSpaceMap::from_used(used, 2000);
+
+        assert_eq!(map.regions(), &[Region::new(756, 244), Region::new(1150, 850)]);
+        assert_eq!(map.free_bytes(), 1094);
+        assert_eq!(map.largest_region(), 850);
+
+        // an entry pointing near the end of the address space just covers the rest of the file.
+        let map = FreeSpaceMap::from_used(vec![Region::new(0, 256), Region::new(1000, u64::MAX)], 2000);
+        assert_eq!(map.regions(), &[Region::new(256, 744)]);
+    }
+
+    #[test]
+    fn test_release() {
+        let mut map = FreeSpaceMap::from_used(vec![Region::new(0, 256), Region::new(300, 700)], 1000);
+        assert_eq!(map.regions(), &[Region::new(256, 44)]);
+
+        // neighbours merge, live data stays used, nothing goes past the end.
+        map.release(&[Region::new(300, 100), Region::new(500, 200), Region::new(900, 500)],
+                    &[Region::new(550, 50)]);
+        assert_eq!(map.regions(), &[
+            Region::new(256, 144),
+            Region::new(500, 50),
+            Region::new(600, 100),
+            Region::new(900, 100),
+        ]);
+    }
+
+    #[test]
+    fn test_allocate_reuses_holes() {
+        let used = vec![Region::new(0, 100), Region::new(150, 100)];
+        let mut allocator = Allocator::new(FreeSpaceMap::from_used(used, 250));
+
+        assert_eq!(allocator.allocate(30), 100);
+        assert_eq!(allocator.allocate(30), 250);
+        assert_eq!(allocator.allocate(20), 130);
+        assert_eq!(allocator.allocate(10), 280);
+    }
+
+    #[test]
+    fn test_allocate_extends_trailing_hole() {
+        let used = vec![Region::new(0, 100)];
+        let mut allocator = Allocator::new(FreeSpaceMap::from_used(used, 150));
+
+        assert_eq!(allocator.allocate(80), 100);
+        assert_eq!(allocator.allocate(10), 180);
+    }
+}
@@ -0,0 +1,645 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self,
+    Read, BufReader,
+    Write, BufWriter,
+    Seek, SeekFrom,
+};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use crate::allocator::{FreeSpaceMap, Region};
+use crate::blowfish::BlowFish;
+use crate::check::{self, CheckReport};
+use crate::client_version::{ClientVersion, SV_T_PATH};
+use crate::extract::{self, ExtractOptions, ExtractReport};
+use crate::header::Header;
+use crate::history::{self, Version};
+use crate::journal;
+use crate::lock::FileLock;
+use crate::options::OpenOptions;
+use crate::orphan::{self, Orphan};
+use crate::progress::Monitor;
+use crate::rekey;
+use crate::stats::{self, Stats};
+use crate::transaction::{self, Operation, Transaction};
+use crate::{split_path, Entry,
+    ENTRY_SIZE, BLOCK_SIZE, SKIP_HEADER_SIZE, PK2_KEYS, DIRECTORY, FILE,
+};
+
+/**
+ * Whether an archive can be changed after opening it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/**
+ * The free space as the last commit left it, along with the size and mtime
+ * the file had then. Anyone else changing the file changes those, and the
+ * next commit scans again.
+ */
+pub(crate) struct CachedFreeSpace {
+    map: FreeSpaceMap,
+    len: u64,
+    modified: SystemTime,
+}
+
+impl CachedFreeSpace {
+    pub(crate) fn new(pk2_path: &str, map: FreeSpaceMap) -> io::Result<Self> {
+        let metadata = fs::metadata(pk2_path)?;
+        Ok(Self { map, len: metadata.len(), modified: metadata.modified()? })
+    }
+
+    fn is_current(&self, pk2_path: &str) -> io::Result<bool> {
+        let metadata = fs::metadata(pk2_path)?;
+        Ok(metadata.len() == self.len && metadata.modified()? == self.modified)
+    }
+}
+
+/**
+ * An archive on disk. Nothing is cached but the root entry and, for the
+ * writer, the free space, every call reads what it needs from the file.
+ * Any number of threads can read at once, changes go through the one
+ * `ArchiveWriter` there can be at a time, see `writer`. Reads only wait
+ * while a commit is being written.
+ */
+pub struct Archive {
+    pub(crate) pk2_path: String,
+    pub(crate) blowfish: BlowFish,
+    pub(crate) root: Entry,
+    pub(crate) options: OpenOptions,
+    header_warnings: Vec<String>,
+    lock: RwLock<()>,
+    writer: Mutex<Option<CachedFreeSpace>>,
+}
+
+impl Archive {
+    /// Same as `open_readonly`, changes need `open_rw`.
+    pub fn open(pk2_path: &str) -> io::Result<Self> {
+        Self::open_readonly(pk2_path)
+    }
+
+    /// Read-only like `open`, changes need `open_rw_with_key`.
+    pub fn open_with_key(pk2_path: &str, key: &[u8]) -> io::Result<Self> {
+        Self::open_with_mode(pk2_path, key, Mode::ReadOnly)
+    }
+
+    pub fn open_rw_with_key(pk2_path: &str, key: &[u8]) -> io::Result<Self> {
+        Self::open_with_mode(pk2_path, key, Mode::ReadWrite)
+    }
+
+    /// Only for reading, every change is refused with `PermissionDenied`.
+    pub fn open_readonly(pk2_path: &str) -> io::Result<Self> {
+        Self::open_with_mode(pk2_path, PK2_KEYS, Mode::ReadOnly)
+    }
+
+    pub fn open_rw(pk2_path: &str) -> io::Result<Self> {
+        Self::open_with_mode(pk2_path, PK2_KEYS, Mode::ReadWrite)
+    }
+
+    /// Read-write fails right away when the file can't be written.
+    /// It also finishes a commit that was interrupted, see `journal`,
+    /// read-only can't and fails instead of reading half of it.
+    pub fn open_with_mode(pk2_path: &str, key: &[u8], mode: Mode) -> io::Result<Self> {
+        OpenOptions::new().key(key).mode(mode).open(pk2_path)
+    }
+
+    /// Reads the header once any interrupted commit is finished, it might have been part of it.
+    pub(crate) fn open_with_options(pk2_path: &str, options: &OpenOptions) -> io::Result<Self> {
+        match options.mode {
+            Mode::ReadOnly => {
+                File::open(pk2_path)?;
+                journal::check_finished(pk2_path)?;
+            },
+            Mode::ReadWrite => {
+                fs::OpenOptions::new().read(true).write(true).open(pk2_path)?;
+                journal::recover(pk2_path)?;
+            },
+        }
+
+        let blowfish = BlowFish::new(&options.key)?;
+        let mut archive = Self {
+            pk2_path: pk2_path.to_string(),
+            blowfish,
+            root: Entry::empty(),
+            options: options.clone(),
+            header_warnings: Vec::new(),
+            lock: RwLock::new(()),
+            writer: Mutex::new(None),
+        };
+
+        let header = Header::from_bytes(&archive.read_bytes(0, SKIP_HEADER_SIZE as u32)?);
+        archive.header_warnings = options.check_header(&header, &archive.blowfish)?;
+        archive.root = archive.get_entries_of_block(SKIP_HEADER_SIZE)?[0];
+        Ok(archive)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.options.mode
+    }
+
+    /// What didn't match the header when the archive was opened leniently, see `OpenOptions`.
+    pub fn header_warnings(&self) -> &[String] {
+        &self.header_warnings
+    }
+
+    pub fn header(&self) -> io::Result<Header> {
+        let _guard = self.read_lock();
+        Ok(Header::from_bytes(&self.read_bytes(0, SKIP_HEADER_SIZE as u32)?))
+    }
+
+    /// The entries of a directory, "." and ".." left out.
+    pub fn list(&self, directory: &str) -> io::Result<Vec<Entry>> {
+        let _guard = self.read_lock();
+        let entry = self.get_entry_of_path(directory)?;
+        if entry.entry_type != DIRECTORY {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} is a file, not a directory.", directory)));
+        }
+        self.read_children(&entry)
+    }
+
+    pub fn extract(&self, path: &str) -> io::Result<(Entry, Vec<u8>)> {
+        let _guard = self.read_lock();
+        let entry = self.get_entry_of_path(path)?;
+        if entry.entry_type != FILE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} is a directory, not a file.", path)));
+        }
+        let bytes = self.read_bytes(entry.position, entry.size)?;
+        Ok((entry, bytes))
+    }
+
+    /// Extracts the file or directory at `path` into `destination`,
+    /// several files at a time, see `ExtractOptions`.
+    pub fn extract_all<P: AsRef<Path>>(&self, path: &str, destination: P,
+                                       options: &ExtractOptions) -> io::Result<ExtractReport> {
+        let _guard = self.read_lock();
+        let entry = self.get_entry_of_path(path)?;
+        extract::extract_all(self, &entry, destination.as_ref(), options)
+    }
+
+    /// The handle every change goes through. Only one can exist at a time,
+    /// in this process through the archive and in others through a file lock,
+    /// asking for a second one waits until the first is dropped. A thread
+    /// that already holds one and asks again waits forever.
+    pub fn writer(&self) -> io::Result<ArchiveWriter<'_>> {
+        self.check_writable()?;
+        // nothing is held in memory, a panicking writer left no state behind.
+        let guard = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let file_lock = FileLock::exclusive(&self.pk2_path)?;
+        Ok(ArchiveWriter { archive: self, free_space: guard, _file_lock: file_lock })
+    }
+
+    /// `writer`, failing with `WouldBlock` instead of waiting when there already is one.
+    pub fn try_writer(&self) -> io::Result<ArchiveWriter<'_>> {
+        self.check_writable()?;
+        let guard = match self.writer.try_lock() {
+            Ok(guard) => guard,
+            // nothing is held in memory, a panicking writer left no state behind.
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                format!("{} already has a writer.", self.pk2_path))),
+        };
+        let file_lock = FileLock::try_exclusive(&self.pk2_path)?;
+        Ok(ArchiveWriter { archive: self, free_space: guard, _file_lock: file_lock })
+    }
+
+    /// Starts keeping the versions `patch` replaces, for `history` and `revert`.
+    /// Their data can't be reused until the archive is compacted.
+    pub fn enable_history(&self) -> io::Result<()> {
+        // a commit in flight records its versions, or doesn't, as a whole.
+        let _writer = self.writer()?;
+        history::enable(&self.pk2_path)
+    }
+
+    /// The old versions of a file, oldest first. Versions stay with the
+    /// path they were recorded under, renaming a file doesn't take them along.
+    pub fn history(&self, path: &str) -> io::Result<Vec<Version>> {
+        let _guard = self.read_lock();
+        let path = history::normalize(path);
+        Ok(history::read(&self.pk2_path)?.into_iter().filter(|version| version.path == path).collect())
+    }
+
+    /// Looks for damage without trusting any of the archive, see `check::Problem`.
+    pub fn check(&self) -> io::Result<CheckReport> {
+        self.check_with_monitor(&Monitor::default())
+    }
+
+    /// `check`, reporting every file it looked at to `monitor`.
+    pub fn check_with_monitor(&self, monitor: &Monitor) -> io::Result<CheckReport> {
+        let _guard = self.read_lock();
+        check::check(self, monitor)
+    }
+
+    fn get_entry_of_path(&self, path: &str) -> io::Result<Entry> {
+        self.find_entry_of_path(path)?.ok_or_else(||
+            io::Error::new(io::ErrorKind::NotFound, format!("Can't find specified path: {}.", path)))
+    }
+
+    fn find_entry_of_path(&self, path: &str) -> io::Result<Option<Entry>> {
+        let mut cursor = self.root;
+        for part in split_path(path) {
+            if cursor.entry_type != DIRECTORY {
+                return Ok(None);
+            }
+            cursor = match self.find_child(&cursor, part)? {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(cursor))
+    }
+
+    fn find_child(&self, directory: &Entry, name: &str) -> io::Result<Option<Entry>> {
+        Ok(self.read_children(directory)?
+               .into_iter()
+               .find(|child| child.name().eq_ignore_ascii_case(name)))
+    }
+
+    pub(crate) fn read_children(&self, entry: &Entry) -> io::Result<Vec<Entry>> {
+        if entry.entry_type != DIRECTORY {
+            return Ok(vec![]);
+        }
+
+        Ok(self.get_blocks_of_node(entry.position)?
+               .into_iter()
+               .flat_map(|(_, entries)| entries)
+               .filter(|child| child.is_used() && !child.is_navigation())
+               .collect())
+    }
+
+    /// Every block of a directory with its 20 entries, empty ones included.
+    /// A directory continues in another block when the last entry of
+    /// the current one has a `next_chain`.
+    pub(crate) fn get_blocks_of_node(&self, position: u64) -> io::Result<Vec<(u64, Vec<Entry>)>> {
+        self.read_blocks_of_node(&mut File::open(&self.pk2_path)?, position)
+    }
+
+    pub(crate) fn get_entries_of_block(&self, offset: u64) -> io::Result<Vec<Entry>> {
+        self.read_entries_of_block(&mut File::open(&self.pk2_path)?, offset)
+    }
+
+    // The two above, through a handle the caller keeps across many blocks.
+    fn read_blocks_of_node(&self, file: &mut File, position: u64) -> io::Result<Vec<(u64, Vec<Entry>)>> {
+        let mut blocks = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = position;
+
+        while current != 0 && visited.insert(current) {
+            let entries = self.read_entries_of_block(file, current)?;
+            let next = entries.last().map_or(0, |entry| entry.next_chain);
+            blocks.push((current, entries));
+            current = next;
+        }
+
+        Ok(blocks)
+    }
+
+    fn read_entries_of_block(&self, file: &mut File, offset: u64) -> io::Result<Vec<Entry>> {
+        let mut bytes = vec![0; BLOCK_SIZE as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+        self.blowfish.decrypt_in_place(&mut bytes)?;
+        Ok(bytes.chunks(ENTRY_SIZE as usize).enumerate().map(|(i, chunk)| {
+            let mut entry = Entry::from_bytes(chunk);
+            entry.offset = offset + i as u64 * ENTRY_SIZE;
+            entry
+        }).collect())
+    }
+
+    /// Scans the header, every entry block reachable from the root,
+    /// every file's data and every old version kept by the history,
+    /// anything else in the archive is free to reuse.
+    pub fn free_space_map(&self) -> io::Result<FreeSpaceMap> {
+        let _guard = self.read_lock();
+        self.scan_free_space()
+    }
+
+    /// What a commit allocates from: the writer's cached map while the file
+    /// is as the last commit left it, a new scan otherwise.
+    pub(crate) fn free_space_for_commit(&self, cached: &Option<CachedFreeSpace>) -> io::Result<FreeSpaceMap> {
+        match cached {
+            Some(cached) if cached.is_current(&self.pk2_path)? => Ok(cached.map.clone()),
+            _ => self.scan_free_space(),
+        }
+    }
+
+    /// All blocks are read through one handle.
+    pub(crate) fn scan_free_space(&self) -> io::Result<FreeSpaceMap> {
+        let mut file = File::open(&self.pk2_path)?;
+        let mut used = vec![Region::new(0, SKIP_HEADER_SIZE)];
+        for version in history::read(&self.pk2_path)? {
+            used.push(Region::new(version.position, version.size as u64));
+        }
+        let mut pending = vec![self.root.position];
+        let mut visited = HashSet::new();
+
+        while let Some(position) = pending.pop() {
+            if !visited.insert(position) {
+                continue;
+            }
+            for (offset, entries) in self.read_blocks_of_node(&mut file, position)? {
+                used.push(Region::new(offset, BLOCK_SIZE));
+                for entry in entries {
+                    if entry.entry_type == FILE {
+                        used.push(Region::new(entry.position, entry.size as u64));
+                    } else if entry.entry_type == DIRECTORY && !entry.is_navigation() {
+                        pending.push(entry.position);
+                    }
+                }
+            }
+        }
+
+        let file_size = file.metadata()?.len();
+        Ok(FreeSpaceMap::from_used(used, file_size))
+    }
+
+    /// Counts and sizes of everything in the archive, in one pass over its entries.
+    pub fn stats(&self) -> io::Result<Stats> {
+        let _guard = self.read_lock();
+        stats::stats(self)
+    }
+
+    /// The free regions with a guess at which file each one belonged to.
+    pub fn orphans(&self) -> io::Result<Vec<Orphan>> {
+        let _guard = self.read_lock();
+        orphan::orphans(self)
+    }
+
+    /// Saves every orphan into `directory`, see `orphans`.
+    pub fn export_orphans<P: AsRef<Path>>(&self, directory: P) -> io::Result<Vec<PathBuf>> {
+        let _guard = self.read_lock();
+        orphan::export(self, directory.as_ref())
+    }
+
+    /// The version in `SV.T`, see `ClientVersion`.
+    pub fn client_version(&self) -> io::Result<ClientVersion> {
+        ClientVersion::from_bytes(&self.extract(SV_T_PATH)?.1)
+    }
+
+    /// Re-encrypts the entries and the header checksum with `new_key`,
+    /// the file data is stored as it is. Returns the archive opened with the new key.
+    pub fn rekey(self, new_key: &[u8]) -> io::Result<Archive> {
+        rekey::rekey(&self, &BlowFish::new(new_key)?)?;
+        let mut options = self.options.clone();
+        options.key(new_key).open(&self.pk2_path)
+    }
+
+    pub(crate) fn read_bytes(&self, offset: u64, count: u32) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; count as usize];
+        let mut reader = BufReader::new(fs::OpenOptions::new().read(true).open(&self.pk2_path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        match self.options.mode {
+            Mode::ReadWrite => Ok(()),
+            Mode::ReadOnly => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                format!("{} is open read-only.", self.pk2_path))),
+        }
+    }
+
+    // The locks only guard the file, a panic while holding one left nothing half done in memory.
+    fn read_lock(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Held while a commit writes, so no read sees half of it.
+    pub(crate) fn write_lock(&self) -> RwLockWriteGuard<'_, ()> {
+        self.lock.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Writes every (offset, bytes) pair through a single handle, in order,
+    /// and only returns once they are on disk.
+    pub(crate) fn write_all_bytes(&self, writes: &[(u64, Vec<u8>)]) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::OpenOptions::new().write(true).open(&self.pk2_path)?);
+        for (offset, buffer) in writes {
+            writer.seek(SeekFrom::Start(*offset))?;
+            writer.write_all(buffer)?;
+        }
+        writer.into_inner().map_err(|err| err.into_error())?.sync_data()
+    }
+}
+
+/**
+ * Exclusive access to change an archive, see `Archive::writer`.
+ * Every change is a transaction of its own unless batched through `transaction`.
+ */
+pub struct ArchiveWriter<'a> {
+    archive: &'a Archive,
+    free_space: MutexGuard<'a, Option<CachedFreeSpace>>,
+    _file_lock: FileLock,
+}
+
+impl<'a> ArchiveWriter<'a> {
+    /// Starts a batch of changes that are only written by `Transaction::commit`.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self.archive, &mut self.free_space)
+    }
+
+    /// Points the file at new data, the old data stays where it is until the
+    /// entry points away from it, so it's only reusable by the next operation.
+    pub fn patch(&mut self, path: &str, buffer: &[u8]) -> io::Result<()> {
+        self.transaction().replace(path, buffer).commit()
+    }
+
+    /// Adds a new file, creating the directories leading to it if needed.
+    pub fn add(&mut self, path: &str, buffer: &[u8]) -> io::Result<()> {
+        self.transaction().add(path, buffer).commit()
+    }
+
+    /// Removes a file or a whole directory, the space it used
+    /// is handed out again by later `add`/`patch` calls.
+    pub fn delete(&mut self, path: &str) -> io::Result<()> {
+        self.transaction().delete(path).commit()
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        self.transaction().rename(from, to).commit()
+    }
+
+    /// Patches `SV.T` to hold `version`, or adds it when there's none.
+    pub fn set_client_version(&mut self, version: ClientVersion) -> io::Result<()> {
+        match self.archive.extract(SV_T_PATH) {
+            Ok((_, original)) => self.patch(SV_T_PATH, &version.into_bytes(Some(&original))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => self.add(SV_T_PATH, &version.into_bytes(None)?),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Raises the client version by one and returns the new one.
+    pub fn bump_client_version(&mut self) -> io::Result<ClientVersion> {
+        let version = self.archive.client_version()?.0.checked_add(1).map(ClientVersion).ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidData, format!("{} already holds the highest version.", SV_T_PATH)))?;
+        self.set_client_version(version)?;
+        Ok(version)
+    }
+
+    /// Points the file back at `history(path)[version]`.
+    /// The data it points at now is recorded as another version.
+    pub fn revert(&mut self, path: &str, version: usize) -> io::Result<()> {
+        self.transaction().revert(path, version).commit()
+    }
+
+    pub(crate) fn apply(&mut self, operations: Vec<Operation>) -> io::Result<()> {
+        transaction::apply(self.archive, &mut self.free_space, operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use super::{Archive, Mode};
+    use crate::allocator::Region;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_add() {
+        let path = create_empty_archive("add");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("textdata/items/sword.txt", b"sword").unwrap();
+        archive.writer().unwrap().add("textdata/shield.txt", b"shield").unwrap();
+
+        let names: Vec<String> = archive.list("textdata").unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["items", "shield.txt"]);
+        assert_eq!(archive.extract("textdata/items/sword.txt").unwrap().1, b"sword");
+        assert!(archive.writer().unwrap().add("textdata/shield.txt", b"again").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_add_chains_new_block() {
+        let path = create_empty_archive("chain");
+        let archive = Archive::open_rw(&path).unwrap();
+        for i in 0..45 {
+            archive.writer().unwrap().add(&format!("many/{}.txt", i), &[i as u8]).unwrap();
+        }
+
+        assert_eq!(archive.list("many").unwrap().len(), 45);
+        assert_eq!(archive.extract("many/44.txt").unwrap().1, vec![44]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_leaves_reusable_space() {
+        let path = create_empty_archive("delete");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", &[1; 100]).unwrap();
+        archive.writer().unwrap().add("b.txt", &[2; 100]).unwrap();
+        archive.writer().unwrap().add("c.txt", &[3; 100]).unwrap();
+        let deleted = archive.extract("a.txt").unwrap().0;
+        archive.writer().unwrap().delete("a.txt").unwrap();
+
+        let names: Vec<String> = archive.list(".").unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["b.txt", "c.txt"]);
+        assert_eq!(archive.free_space_map().unwrap().regions(), &[Region::new(deleted.position, 100)]);
+
+        // patching into the hole instead of growing the archive.
+        let size = fs::metadata(&path).unwrap().len();
+        archive.writer().unwrap().patch("c.txt", &[4; 60]).unwrap();
+        let (patched, content) = archive.extract("c.txt").unwrap();
+        assert_eq!(patched.position, deleted.position);
+        assert_eq!(content, vec![4; 60]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cached_free_space() {
+        let path = create_empty_archive("cached-free-space");
+        let archive = Archive::open_rw(&path).unwrap();
+        let mut writer = archive.writer().unwrap();
+        let check = |writer: &super::ArchiveWriter| {
+            let cached = writer.free_space.as_ref().unwrap().map.regions().to_vec();
+            assert_eq!(cached, archive.scan_free_space().unwrap().regions());
+        };
+
+        for i in 0..25 {
+            writer.add(&format!("dir/sub/{}.txt", i), &[i as u8; 300]).unwrap();
+        }
+        check(&writer);
+        writer.patch("dir/sub/3.txt", &[1; 50]).unwrap();
+        writer.delete("dir/sub/4.txt").unwrap();
+        check(&writer);
+        writer.transaction().replace("dir/sub/5.txt", b"new").revert("dir/sub/5.txt", 0).commit().unwrap();
+        check(&writer);
+        writer.rename("dir/sub", "moved").unwrap();
+        writer.delete("dir").unwrap();
+        writer.delete("moved").unwrap();
+        check(&writer);
+
+        // someone else writing makes the next commit scan again.
+        drop(writer);
+        let other = Archive::open_rw(&path).unwrap();
+        other.writer().unwrap().add("other.txt", &[2; 1000]).unwrap();
+        let mut writer = archive.writer().unwrap();
+        writer.add("mine.txt", b"mine").unwrap();
+        check(&writer);
+        assert_eq!(archive.extract("other.txt").unwrap().1, vec![2; 1000]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_single_writer() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Archive>();
+
+        let path = create_empty_archive("writer");
+        let archive = Archive::open_rw(&path).unwrap();
+        let other = Archive::open_rw(&path).unwrap();
+        let mut writer = archive.writer().unwrap();
+        assert_eq!(archive.try_writer().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(other.try_writer().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+        // readers don't wait for the writer, only for its commits.
+        writer.add("a.txt", b"a").unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(archive.extract("a.txt").unwrap().1, b"a"));
+            }
+        });
+
+        drop(writer);
+        other.try_writer().unwrap().add("b.txt", b"b").unwrap();
+        assert_eq!(archive.list(".").unwrap().len(), 2);
+
+        // a second writer waits for the first instead of failing.
+        let writer = archive.writer().unwrap();
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| other.writer().unwrap().add("c.txt", b"c"));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            drop(writer);
+            waiting.join().unwrap().unwrap();
+        });
+        assert_eq!(archive.list(".").unwrap().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_readonly() {
+        let path = create_empty_archive("readonly");
+        Archive::open_rw(&path).unwrap().writer().unwrap().add("a.txt", b"a").unwrap();
+        crate::journal::write(&path, &[]).unwrap();
+
+        let archive = Archive::open_readonly(&path).unwrap();
+        assert_eq!(archive.mode(), Mode::ReadOnly);
+        assert_eq!(archive.extract("a.txt").unwrap().1, b"a");
+        assert_eq!(archive.writer().err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(archive.enable_history().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        // nothing is written, not even to finish a commit.
+        assert!(fs::metadata(crate::journal::journal_path(&path)).is_ok());
+
+        Archive::open_rw(&path).unwrap();
+        assert!(fs::metadata(crate::journal::journal_path(&path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
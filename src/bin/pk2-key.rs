@@ -0,0 +1,73 @@
+use std::env;
+use std::fs;
+use std::process;
+
+use pk2::{find_key, Candidates};
+
+const USAGE: &str = "\
+Looks for the Blowfish key of a PK2 archive.
+
+Usage: pk2-key <archive> (--wordlist <file> | --digits <min>-<max>) [--threads <n>]";
+
+fn main() {
+    if let Err(message) = run(env::args().skip(1).collect()) {
+        eprintln!("{}", message);
+        process::exit(2);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut archive = None;
+    let mut candidates = None;
+    let mut threads = 0;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value.\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--wordlist" => {
+                let path = value()?;
+                let text = fs::read(&path).map_err(|err| format!("{}: {}", path, err))?;
+                candidates = Some(Candidates::from_wordlist(&text));
+            },
+            "--digits" => {
+                let range = value()?;
+                let (min, max) = range.split_once('-').unwrap_or((&range, &range));
+                match (min.parse(), max.parse()) {
+                    (Ok(min), Ok(max)) => candidates = Some(Candidates::Digits { min, max }),
+                    _ => return Err(format!("Invalid digit range: {}.", range)),
+                }
+            },
+            "--threads" => {
+                let count = value()?;
+                threads = count.parse().map_err(|_| format!("Invalid thread count: {}.", count))?;
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if archive.is_none() && !arg.starts_with('-') => archive = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}.\n\n{}", arg, USAGE)),
+        }
+    }
+
+    let (archive, candidates) = match (archive, candidates) {
+        (Some(archive), Some(candidates)) => (archive, candidates),
+        _ => return Err(USAGE.to_string()),
+    };
+    match find_key(&archive, &candidates, threads).map_err(|err| format!("{}: {}", archive, err))? {
+        Some(found) => {
+            let key = String::from_utf8_lossy(&found.key);
+            let hex: Vec<String> = found.key.iter().map(|byte| format!("{:02X}", byte)).collect();
+            println!("Key: {:?} ({})", key, hex.join(" "));
+            if !found.checksum {
+                println!("The header checksum doesn't match, the client changed its plaintext.");
+            }
+            Ok(())
+        },
+        None => {
+            println!("None of the candidates is the key.");
+            process::exit(1);
+        },
+    }
+}
@@ -1,7 +1,10 @@
-use std::num::Wrapping;
+//! Blowfish as JoyMax uses it for PK2 entries and the rest of the client's
+//! data, and in its standard form.
+
+use std::io;
 
 // const BLOCK_SIZE    : u32 = 8;
-// const MAX_KEY_LENGTH: u32 = 0x38;
+const MAX_KEY_LENGTH: usize = 0x38;
 // const PBOX_ENTRIES  : u32 = 0x12;
 // const SBOX_ENTRIES  : u32 = 0x100;
 
@@ -87,8 +90,33 @@ const SBOX_INIT_4: [u32; 256] = [
     0x85cbfe4e, 0x8ae88dd8, 0x7aaaf9b0, 0x4cf9aa7e, 0x1948c25c, 0x2fb8a8c, 0x1c36ae4, 0xd6ebe1f9, 0x90d4f869, 0xa65cdea0, 0x3f09252d, 0xc208e69f, 0xb74e6132, 0xce77e25b, 0x578fdfe3, 0x3ac372e6
 ];
 
+/**
+ * How `encrypt_padded` fills up the last block and `decrypt_padded` takes it off.
+ * Zeros can't be told apart from the data, so they are left in place when decrypting.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Padding {
+    /// The input has to be a multiple of 8 bytes already.
+    None,
+    Zero,
+    /// 1 to 8 bytes, each holding the padding's length.
+    Pkcs7,
+}
+
+/**
+ * How the two 32 bit halves of a block are read from and written to bytes.
+ * PK2 entries use JoyMax's little endian words, everything else, the network
+ * protocol included, the big endian words of standard Blowfish.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Variant {
+    Standard,
+    #[default]
+    JoyMax,
+}
+
 pub struct BlowFish {
-    block: [u8 ; 0x8],
+    variant: Variant,
     pbox : [u32; 0x12],
     sbox1: [u32; 0x100],
     sbox2: [u32; 0x100],
@@ -97,9 +125,15 @@ pub struct BlowFish {
 }
 
 impl BlowFish {
-    pub fn new(key: &[u8], offset: i32, length: i32) -> Self {
+    /// `key` has to be 1 to 56 bytes long.
+    pub fn new(key: &[u8]) -> io::Result<Self> {
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            let message = format!("A key is 1 to {} bytes, not {}.", MAX_KEY_LENGTH, key.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
         let mut blowfish = Self {
-            block: [0; 0x8],
+            variant: Variant::default(),
             pbox : [0; 0x12],
             sbox1: [0; 0x100],
             sbox2: [0; 0x100],
@@ -107,207 +141,263 @@ impl BlowFish {
             sbox4: [0; 0x100],
         };
 
-        blowfish.initialize(key, offset, length);
+        blowfish.initialize(key);
 
-        blowfish
+        Ok(blowfish)
     }
-    
-    fn initialize(&mut self, key: &[u8], offset: i32, length: i32) {
+
+    /// Uses the `length` bytes of `key` from `offset` on as the key.
+    pub fn with_key_range(key: &[u8], offset: usize, length: usize) -> io::Result<Self> {
+        match offset.checked_add(length).and_then(|end| key.get(offset..end)) {
+            Some(key) => Self::new(key),
+            None => {
+                let message = format!("{} bytes from {} are outside the {} byte key.", length, offset, key.len());
+                Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+            },
+        }
+    }
+
+    /// `Variant::JoyMax` unless set.
+    pub fn variant(&mut self, variant: Variant) -> &mut Self {
+        self.variant = variant;
+        self
+    }
+
+    fn initialize(&mut self, key: &[u8]) {
         self.pbox.clone_from_slice(&PBOX_INIT);
         self.sbox1.clone_from_slice(&SBOX_INIT_1);
         self.sbox2.clone_from_slice(&SBOX_INIT_2);
         self.sbox3.clone_from_slice(&SBOX_INIT_3);
         self.sbox4.clone_from_slice(&SBOX_INIT_4);
 
-        if length == 0 {
-            return;
-        }
-
-        let mut index = offset;
-        let num2 = offset + length;
+        // the key is repeated until it covers the whole pbox.
+        let mut key = key.iter().cycle();
         let mut num3: u32 = 0;
         for i in 0..0x12 {
             for _ in 0..0x4 {
-                num3 = (num3 << 8) | key[index as usize] as u32;
-                index += 1;
-                if index == num2 {
-                    index = offset;
-                }
+                num3 = (num3 << 8) | *key.next().unwrap() as u32;
             }
             self.pbox[i] ^= num3;
         }
 
-        let mut hi: u32 = 0;
-        let mut lo: u32 = 0;
+        // each pair of subkeys is the previous pair encrypted, starting from zeros.
+        let (mut hi, mut lo) = (0, 0);
         for j in (0..0x12).step_by(2) {
-            self.encrypt_block(&mut hi, &mut lo);
+            (hi, lo) = self.encipher(hi, lo);
             self.pbox[j] = hi;
             self.pbox[j + 1] = lo;
         }
 
         for j in (0..0x100).step_by(2) {
-            self.encrypt_block(&mut hi, &mut lo);
+            (hi, lo) = self.encipher(hi, lo);
             self.sbox1[j] = hi;
             self.sbox1[j + 1] = lo;
         }
         
         for j in (0..0x100).step_by(2) {
-            self.encrypt_block(&mut hi, &mut lo);
+            (hi, lo) = self.encipher(hi, lo);
             self.sbox2[j] = hi;
             self.sbox2[j + 1] = lo;
         }
 
         for j in (0..0x100).step_by(2) {
-            self.encrypt_block(&mut hi, &mut lo);
+            (hi, lo) = self.encipher(hi, lo);
             self.sbox3[j] = hi;
             self.sbox3[j + 1] = lo;
         }
 
         for j in (0..0x100).step_by(2) {
-            self.encrypt_block(&mut hi, &mut lo);
+            (hi, lo) = self.encipher(hi, lo);
             self.sbox4[j] = hi;
             self.sbox4[j + 1] = lo;
         }
-
     }
 
-    fn encrypt_block(&mut self, hi: &mut u32, lo: &mut u32) {
-
-        let _hi = self.u32_to_four_byte(*hi);
-        let _lo = self.u32_to_four_byte(*lo);
-        self.block[0] = _hi.0;
-        self.block[1] = _hi.1;
-        self.block[2] = _hi.2;
-        self.block[3] = _hi.3;
-        self.block[4] = _lo.0;
-        self.block[5] = _lo.1;
-        self.block[6] = _lo.2;
-        self.block[7] = _lo.3;
-
-        self._encrypt_block();
-
-        *hi = self.four_byte_to_u32(self.block[0], self.block[1], self.block[2], self.block[3]);
-        *lo = self.four_byte_to_u32(self.block[4], self.block[5], self.block[6], self.block[7]);
+    /// Fails without touching `data` unless it's a multiple of 8 bytes long.
+    pub fn encrypt_in_place(&self, data: &mut [u8]) -> io::Result<()> {
+        check_blocks(data.len())?;
+        for block in data.chunks_exact_mut(8) {
+            let (left, right) = read_block(self.variant, block);
+            let (left, right) = self.encipher(left, right);
+            write_block(self.variant, block, left, right);
+        }
+        Ok(())
     }
 
-    fn _encrypt_block(&mut self) {
-        let mut num1 = self.four_byte_to_u32(self.block[0], self.block[1], self.block[2], self.block[3]);
-        let mut num2 = self.four_byte_to_u32(self.block[4], self.block[5], self.block[6], self.block[7]);
+    /// Fails without touching `data` unless it's a multiple of 8 bytes long.
+    pub fn decrypt_in_place(&self, data: &mut [u8]) -> io::Result<()> {
+        check_blocks(data.len())?;
+        for block in data.chunks_exact_mut(8) {
+            let (left, right) = read_block(self.variant, block);
+            let (left, right) = self.decipher(left, right);
+            write_block(self.variant, block, left, right);
+        }
+        Ok(())
+    }
 
-        num1 ^= self.pbox.first().unwrap();
-        for i in (1..17).step_by(2) {
-            num2 ^= self.helper(num1, self.pbox[i]);
-            num1 ^= self.helper(num2, self.pbox[i + 1]);
+    pub fn encrypt_padded(&self, input: &[u8], padding: Padding) -> io::Result<Vec<u8>> {
+        let mut output = input.to_vec();
+        match padding {
+            Padding::None => {},
+            Padding::Zero => output.resize(input.len().div_ceil(8) * 8, 0),
+            Padding::Pkcs7 => {
+                let length = 8 - input.len() % 8;
+                output.resize(input.len() + length, length as u8);
+            },
         }
-        num2 ^= self.pbox.last().unwrap();
+        self.encrypt_in_place(&mut output)?;
+        Ok(output)
+    }
 
-        let num1 = self.u32_to_four_byte(num1);
-        let num2 = self.u32_to_four_byte(num2);
-        self.block[0] = num2.0;
-        self.block[1] = num2.1;
-        self.block[2] = num2.2;
-        self.block[3] = num2.3;
-        self.block[4] = num1.0;
-        self.block[5] = num1.1;
-        self.block[6] = num1.2;
-        self.block[7] = num1.3;
+    pub fn decrypt_padded(&self, input: &[u8], padding: Padding) -> io::Result<Vec<u8>> {
+        let mut output = input.to_vec();
+        self.decrypt_in_place(&mut output)?;
+        if padding == Padding::Pkcs7 {
+            let length = output.last().map_or(0, |length| *length as usize);
+            if length == 0 || length > 8 || length > output.len()
+                || output[output.len() - length..].iter().any(|byte| *byte as usize != length) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid padding."));
+            }
+            output.truncate(output.len() - length);
+        }
+        Ok(output)
     }
 
-    pub fn encrypt(&self, input: &[u8], count: u32) -> [u8; 128] {
-        let mut output: [u8; 128] = [0; 128];
+    /*
+     * The chained modes work on a whole message at once, in place, and start
+     * from `iv`. CBC needs whole blocks, CFB and OFB (64 bit feedback) take
+     * any length and OFB decrypts with the same call it encrypts with.
+     */
+    pub fn encrypt_cbc(&self, iv: &[u8; 8], data: &mut [u8]) -> io::Result<()> {
+        check_blocks(data.len())?;
+        let mut previous = *iv;
+        for block in data.chunks_exact_mut(8) {
+            block.iter_mut().zip(previous.iter()).for_each(|(byte, chain)| *byte ^= chain);
+            self.encrypt_single(&mut previous, block);
+            block.copy_from_slice(&previous);
+        }
+        Ok(())
+    }
 
-        for i in (0..count).step_by(8) {
-            let i = i as usize;
+    pub fn decrypt_cbc(&self, iv: &[u8; 8], data: &mut [u8]) -> io::Result<()> {
+        check_blocks(data.len())?;
+        let mut previous = *iv;
+        for block in data.chunks_exact_mut(8) {
+            let mut plain = [0; 8];
+            self.decrypt_single(&mut plain, block);
+            plain.iter_mut().zip(previous.iter()).for_each(|(byte, chain)| *byte ^= chain);
+            previous.copy_from_slice(block);
+            block.copy_from_slice(&plain);
+        }
+        Ok(())
+    }
 
-            let mut num1 = self.four_byte_to_u32(input[i + 3], input[i + 2], input[i + 1], input[i + 0]);
-            let mut num2 = self.four_byte_to_u32(input[i + 7], input[i + 6], input[i + 5], input[i + 4]);
+    pub fn encrypt_cfb(&self, iv: &[u8; 8], data: &mut [u8]) {
+        let mut register = *iv;
+        for chunk in data.chunks_mut(8) {
+            let mut stream = [0; 8];
+            self.encrypt_single(&mut stream, &register);
+            chunk.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
+            register[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
 
-            num1 ^= self.pbox.first().unwrap();
-            for i in (1..17).step_by(2) {
-                num2 ^= self.helper(num1, self.pbox[i]);
-                num1 ^= self.helper(num2, self.pbox[i + 1]);
-            }
-            num2 ^= self.pbox.last().unwrap();
-
-            let num1 = self.u32_to_four_byte(num1);
-            let num2 = self.u32_to_four_byte(num2);
-            output[i + 3] = num2.0;
-            output[i + 2] = num2.1;
-            output[i + 1] = num2.2;
-            output[i + 0] = num2.3;
-            output[i + 7] = num1.0;
-            output[i + 6] = num1.1;
-            output[i + 5] = num1.2;
-            output[i + 4] = num1.3;
+    pub fn decrypt_cfb(&self, iv: &[u8; 8], data: &mut [u8]) {
+        let mut register = *iv;
+        for chunk in data.chunks_mut(8) {
+            let mut stream = [0; 8];
+            self.encrypt_single(&mut stream, &register);
+            register[..chunk.len()].copy_from_slice(chunk);
+            chunk.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
         }
+    }
 
-        output
+    pub fn apply_ofb(&self, iv: &[u8; 8], data: &mut [u8]) {
+        let mut stream = *iv;
+        for chunk in data.chunks_mut(8) {
+            let register = stream;
+            self.encrypt_single(&mut stream, &register);
+            chunk.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
+        }
     }
 
-    pub fn decrypt(&self, input: &[u8], count: u32) -> [u8; 128] {
-        let mut output: [u8; 128] = [0; 128];
+    // `input` is a single 8 byte block.
+    fn encrypt_single(&self, output: &mut [u8; 8], input: &[u8]) {
+        let (left, right) = read_block(self.variant, input);
+        let (left, right) = self.encipher(left, right);
+        write_block(self.variant, output, left, right);
+    }
 
-        for i in (0..count).step_by(8) {
-            let i = i as usize;
-            
-            let mut num1 = self.four_byte_to_u32(input[i + 3], input[i + 2], input[i + 1], input[i + 0]);
-            let mut num2 = self.four_byte_to_u32(input[i + 7], input[i + 6], input[i + 5], input[i + 4]);
+    fn decrypt_single(&self, output: &mut [u8; 8], input: &[u8]) {
+        let (left, right) = read_block(self.variant, input);
+        let (left, right) = self.decipher(left, right);
+        write_block(self.variant, output, left, right);
+    }
 
-            num1 ^= self.pbox.last().unwrap();
-            for i in (1..17).rev().step_by(2) {
-                num2 ^= self.helper(num1, self.pbox[i]);
-                num1 ^= self.helper(num2, self.pbox[i - 1]);
-            }
-            num2 ^= self.pbox.first().unwrap();
-
-            let num1 = self.u32_to_four_byte(num1);
-            let num2 = self.u32_to_four_byte(num2);
-            output[i + 3] = num2.0;
-            output[i + 2] = num2.1;
-            output[i + 1] = num2.2;
-            output[i + 0] = num2.3;
-            output[i + 7] = num1.0;
-            output[i + 6] = num1.1;
-            output[i + 5] = num1.2;
-            output[i + 4] = num1.3;
+    fn encipher(&self, mut num1: u32, mut num2: u32) -> (u32, u32) {
+        num1 ^= self.pbox.first().unwrap();
+        for i in (1..17).step_by(2) {
+            num2 ^= self.helper(num1, self.pbox[i]);
+            num1 ^= self.helper(num2, self.pbox[i + 1]);
         }
+        num2 ^= self.pbox.last().unwrap();
+        (num2, num1)
+    }
 
-        output
+    fn decipher(&self, mut num1: u32, mut num2: u32) -> (u32, u32) {
+        num1 ^= self.pbox.last().unwrap();
+        for i in (1..17).rev().step_by(2) {
+            num2 ^= self.helper(num1, self.pbox[i]);
+            num1 ^= self.helper(num2, self.pbox[i - 1]);
+        }
+        num2 ^= self.pbox.first().unwrap();
+        (num2, num1)
     }
 
     fn helper(&self, number: u32, other: u32) -> u32 {
-        let first : u32 = (Wrapping(self.sbox1[(number >> 0x18) as usize]) + Wrapping(self.sbox2[((number >> 0x10) & 0xff_u32) as usize])).0;
-        let second = self.sbox3[((number >> 8) as usize) & 0xff];
-        let third  = self.sbox4[(number as usize) & 0xff];
-        (Wrapping(first ^ second) + Wrapping(third)).0 ^ other
+        let first  = self.sbox1[(number >> 0x18) as usize].wrapping_add(self.sbox2[((number >> 0x10) & 0xff) as usize]);
+        let second = self.sbox3[((number >> 0x08) & 0xff) as usize];
+        let third  = self.sbox4[(number & 0xff) as usize];
+        (first ^ second).wrapping_add(third) ^ other
     }
+}
 
-    fn u32_to_four_byte(&self, number: u32) -> (u8, u8, u8, u8) {
-        (
-            ((number >> 0x18) as u8), 
-            ((number >> 0x10) as u8),
-            ((number >> 0x08) as u8),
-            ((number >> 0x00) as u8),
-        )
+fn check_blocks(length: usize) -> io::Result<()> {
+    if !length.is_multiple_of(8) {
+        let message = format!("{} bytes aren't a multiple of the 8 byte block.", length);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
     }
+    Ok(())
+}
 
-    fn four_byte_to_u32(&self, b1: u8, b2: u8, b3: u8, b4: u8) -> u32 {
-        ((((b1 as u32) << 24) | ((b2 as u32) << 16)) | ((b3 as u32) << 8)) | (b4 as u32)
+fn read_block(variant: Variant, block: &[u8]) -> (u32, u32) {
+    let left = [block[0], block[1], block[2], block[3]];
+    let right = [block[4], block[5], block[6], block[7]];
+    match variant {
+        Variant::Standard => (u32::from_be_bytes(left), u32::from_be_bytes(right)),
+        Variant::JoyMax => (u32::from_le_bytes(left), u32::from_le_bytes(right)),
     }
-
 }
 
-
+fn write_block(variant: Variant, block: &mut [u8], left: u32, right: u32) {
+    let (left, right) = match variant {
+        Variant::Standard => (left.to_be_bytes(), right.to_be_bytes()),
+        Variant::JoyMax => (left.to_le_bytes(), right.to_le_bytes()),
+    };
+    block[..4].copy_from_slice(&left);
+    block[4..].copy_from_slice(&right);
+}
 
 #[cfg(test)]
 mod tests {
-    use super::BlowFish;
+    use std::io;
+    use super::{BlowFish, Padding, Variant};
     
     #[test]
     fn test_encrypt() {
 
         let keys: [u8; 6] = [0x32, 0xCE, 0xDD, 0x7C, 0xBC, 0xA8];
-        let blowfish = BlowFish::new(&keys, 0, 6);
+        let blowfish = BlowFish::new(&keys).unwrap();
 
         let input: Vec<u8> = (0..128).map(|i| i as u8 ).collect();
 
@@ -319,7 +409,8 @@ mod tests {
             130, 150, 124, 196, 33, 188, 97, 109, 22, 248, 95, 55, 0, 45, 151, 108, 42, 47, 112, 150, 192, 249, 100, 2, 116
         ];
         
-        let output = blowfish.encrypt(input.as_slice(), 128);
+        let mut output = input.clone();
+        blowfish.encrypt_in_place(&mut output).unwrap();
 
         assert_eq!(expected.len(), 128);
 
@@ -333,7 +424,7 @@ mod tests {
     fn test_decrypt() {
 
         let keys: [u8; 6] = [0x32, 0xCE, 0xDD, 0x7C, 0xBC, 0xA8];
-        let blowfish = BlowFish::new(&keys, 0, 6);
+        let blowfish = BlowFish::new(&keys).unwrap();
 
         let input: [u8; 128] = [
             165, 81, 153, 215, 209, 191, 50, 206, 34, 228, 46, 95, 232, 110, 180, 237, 206, 74, 218, 59, 108, 141, 8, 196, 48, 66, 
@@ -345,7 +436,8 @@ mod tests {
         
         let expected: Vec<u8> = (0..128).map(|i| i as u8 ).collect();
 
-        let output = blowfish.decrypt(&input, 128);
+        let mut output = input;
+        blowfish.decrypt_in_place(&mut output).unwrap();
 
         assert_eq!(expected.len(), 128);
 
@@ -354,4 +446,138 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn test_in_place_and_padding() {
+        let blowfish = BlowFish::new(crate::PK2_KEYS).unwrap();
+        let input: Vec<u8> = (0..128).map(|i| i as u8).collect();
+
+        let mut data = input.clone();
+        blowfish.encrypt_in_place(&mut data).unwrap();
+        assert_eq!(data, blowfish.encrypt_padded(&input, Padding::None).unwrap());
+        blowfish.decrypt_in_place(&mut data).unwrap();
+        assert_eq!(data, input);
+
+        let err = blowfish.encrypt_in_place(&mut data[..13]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(data, input);
+        assert!(blowfish.encrypt_padded(&input[..13], Padding::None).is_err());
+
+        let encrypted = blowfish.encrypt_padded(&input[..13], Padding::Zero).unwrap();
+        assert_eq!(encrypted.len(), 16);
+        let decrypted = blowfish.decrypt_padded(&encrypted, Padding::Zero).unwrap();
+        assert_eq!(&decrypted[..13], &input[..13]);
+        assert_eq!(&decrypted[13..], &[0; 3]);
+
+        for length in [0, 7, 8, 13] {
+            let encrypted = blowfish.encrypt_padded(&input[..length], Padding::Pkcs7).unwrap();
+            assert_eq!(encrypted.len(), length / 8 * 8 + 8);
+            assert_eq!(blowfish.decrypt_padded(&encrypted, Padding::Pkcs7).unwrap(), &input[..length]);
+        }
+        let err = blowfish.decrypt_padded(&blowfish.encrypt_padded(&input[..8], Padding::None).unwrap(), Padding::Pkcs7).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_key_validation() {
+        assert!(BlowFish::new(&[]).is_err());
+        assert!(BlowFish::new(&[1; 56]).is_ok());
+        assert!(BlowFish::new(&[1; 57]).is_err());
+        assert!(BlowFish::with_key_range(b"SILKROADVERSION", 8, 8).is_err());
+        assert!(BlowFish::with_key_range(b"SILKROADVERSION", usize::MAX, 2).is_err());
+        assert!(BlowFish::with_key_range(b"SILKROADVERSION", 0, 0).is_err());
+
+        let input = [7; 8];
+        let whole = BlowFish::new(b"SILKROAD").unwrap().encrypt_padded(&input, Padding::None).unwrap();
+        let part = BlowFish::with_key_range(b"SILKROADVERSION", 0, 8).unwrap().encrypt_padded(&input, Padding::None).unwrap();
+        assert_eq!(whole, part);
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // Eric Young's test vectors, published with Schneier's reference code.
+    #[test]
+    fn test_standard_vectors() {
+        let vectors = [
+            ("0000000000000000", "0000000000000000", "4EF997456198DD78"),
+            ("FFFFFFFFFFFFFFFF", "FFFFFFFFFFFFFFFF", "51866FD5B85ECB8A"),
+            ("3000000000000000", "1000000000000001", "7D856F9A613063F2"),
+            ("1111111111111111", "1111111111111111", "2466DD878B963C9D"),
+            ("0123456789ABCDEF", "1111111111111111", "61F9C3802281B096"),
+            ("1111111111111111", "0123456789ABCDEF", "7D0CC630AFDA1EC7"),
+            ("FEDCBA9876543210", "0123456789ABCDEF", "0ACEAB0FC6A0A28D"),
+            ("7CA110454A1A6E57", "01A1D6D039776742", "59C68245EB05282B"),
+            ("0131D9619DC1376E", "5CD54CA83DEF57DA", "B1B8CC0B250F09A0"),
+            ("F0E1D2C3B4A59687", "FEDCBA9876543210", "E87A244E2CC85E82"),
+        ];
+        for (key, plain, cipher) in vectors.iter() {
+            let mut blowfish = BlowFish::new(&hex(key)).unwrap();
+            blowfish.variant(Variant::Standard);
+            let mut data = hex(plain);
+            blowfish.encrypt_in_place(&mut data).unwrap();
+            assert_eq!(data, hex(cipher), "key {}", key);
+            blowfish.decrypt_in_place(&mut data).unwrap();
+            assert_eq!(data, hex(plain), "key {}", key);
+        }
+
+        // keys of 1 to 24 bytes, all taken from the start of the same one.
+        let key = hex("F0E1D2C3B4A5968778695A4B3C2D1E0F0011223344556677");
+        let ciphers = [
+            "F9AD597C49DB005E", "E91D21C1D961A6D6", "E9C2B70A1BC65CF3", "BE1E639408640F05",
+            "B39E44481BDB1E6E", "9457AA83B1928C0D", "8BB77032F960629D", "E87A244E2CC85E82",
+            "15750E7A4F4EC577", "122BA70B3AB64AE0", "3A833C9AFFC537F6", "9409DA87A90F6BF2",
+            "884F80625060B8B4", "1F85031C19E11968", "79D9373A714CA34F", "93142887EE3BE15C",
+            "03429E838CE2D14B", "A4299E27469FF67B", "AFD5AED1C1BC96A8", "10851C0E3858DA9F",
+            "E6F51ED79B9DB21F", "64A6E14AFD36B46F", "80C7D7D45A5479AD", "05044B62FA52D080",
+        ];
+        for (length, cipher) in ciphers.iter().enumerate() {
+            let mut blowfish = BlowFish::with_key_range(&key, 0, length + 1).unwrap();
+            blowfish.variant(Variant::Standard);
+            let mut data = hex("FEDCBA9876543210");
+            blowfish.encrypt_in_place(&mut data).unwrap();
+            assert_eq!(data, hex(cipher), "key length {}", length + 1);
+        }
+    }
+
+    #[test]
+    fn test_variants_differ_in_byte_order() {
+        let mut blowfish = BlowFish::new(crate::PK2_KEYS).unwrap();
+        let joymax = blowfish.encrypt_padded(b"JoyMax!!", Padding::None).unwrap();
+        blowfish.variant(Variant::Standard);
+        let mut swapped = *b"MyoJ!!xa";
+        blowfish.encrypt_in_place(&mut swapped).unwrap();
+        let expected: Vec<u8> = joymax.chunks(4).flat_map(|word| word.iter().rev().copied()).collect();
+        assert_eq!(swapped.to_vec(), expected);
+    }
+
+    // Eric Young's chained mode vectors: the message is NUL terminated and CBC pads it with zeros.
+    #[test]
+    fn test_chained_modes() {
+        let mut blowfish = BlowFish::new(&hex("0123456789ABCDEFF0E1D2C3B4A59687")).unwrap();
+        blowfish.variant(Variant::Standard);
+        let iv = [0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32, 0x10];
+        let message = b"7654321 Now is the time for \0";
+
+        let mut data = message.to_vec();
+        data.resize(32, 0);
+        blowfish.encrypt_cbc(&iv, &mut data).unwrap();
+        assert_eq!(data, hex("6B77B4D63006DEE605B156E27403979358DEB9E7154616D959F1652BD5FF92CC"));
+        blowfish.decrypt_cbc(&iv, &mut data).unwrap();
+        assert_eq!(&data[..29], &message[..]);
+        assert!(blowfish.encrypt_cbc(&iv, &mut data[..29]).is_err());
+
+        let mut data = message.to_vec();
+        blowfish.encrypt_cfb(&iv, &mut data);
+        assert_eq!(data, hex("E73214A2822139CAF26ECF6D2EB9E76E3DA3DE04D1517200519D57A6C3"));
+        blowfish.decrypt_cfb(&iv, &mut data);
+        assert_eq!(&data[..], &message[..]);
+
+        let mut data = message.to_vec();
+        blowfish.apply_ofb(&iv, &mut data);
+        assert_eq!(data, hex("E73214A2822139CA62B343CC5B65587310DD908D0C241B2263C2CF80DA"));
+        blowfish.apply_ofb(&iv, &mut data);
+        assert_eq!(&data[..], &message[..]);
+    }
 }
@@ -0,0 +1,245 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+use crate::blowfish::BlowFish;
+use crate::header::Header;
+use crate::progress::Monitor;
+use crate::writer::{self, Node};
+use crate::{split_new_path, Entry, DIRECTORY, FILE, FILETIME_UNIX_EPOCH};
+
+/**
+ * Collects files into a tree and writes them out as a brand new archive,
+ * encrypted with the given key.
+ * Packing the same files twice gives the same bytes: entries are sorted by name
+ * and every entry gets the same timestamp instead of the current time.
+ */
+pub struct Pk2Builder {
+    blowfish: BlowFish,
+    root: Node,
+    timestamp: u64,
+    monitor: Monitor,
+}
+
+impl Pk2Builder {
+    pub fn new(key: &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            blowfish: BlowFish::new(key)?,
+            root: Node::directory(Entry::new(DIRECTORY, ".", 0, 0)?, Vec::new()),
+            timestamp: FILETIME_UNIX_EPOCH,
+            monitor: Monitor::default(),
+        })
+    }
+
+    /// The FILETIME written as every entry's access, create and modify date,
+    /// 1970-01-01 unless set.
+    pub fn timestamp(&mut self, timestamp: u64) -> &mut Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Gets every file `write` packs.
+    pub fn monitor(&mut self, monitor: Monitor) -> &mut Self {
+        self.monitor = monitor;
+        self
+    }
+
+    /// Adds a file at `path` inside the archive, `data` is only read by `write`.
+    pub fn add_file<R: Read + 'static>(&mut self, path: &str, data: R) -> io::Result<&mut Self> {
+        let path_parts = split_new_path(path)?;
+        let (name, directories) = path_parts.split_last().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, "Empty path."))?;
+
+        let mut cursor = &mut self.root;
+        for directory in directories {
+            let index = match find(cursor, directory) {
+                Some(index) if cursor.children[index].entry.entry_type == DIRECTORY => index,
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("{} is a file, not a directory.", directory))),
+                None => {
+                    cursor.children.push(Node::directory(Entry::new(DIRECTORY, directory, 0, 0)?, Vec::new()));
+                    cursor.children.len() - 1
+                },
+            };
+            cursor = &mut cursor.children[index];
+        }
+
+        if find(cursor, name).is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists.", path)));
+        }
+        cursor.children.push(Node::file(Entry::new(FILE, name, 0, 0)?, Box::new(data)));
+        Ok(self)
+    }
+
+    /// Adds every (path, reader) pair, as `add_file` would.
+    pub fn add_files<I, P, R>(&mut self, files: I) -> io::Result<&mut Self>
+        where I: IntoIterator<Item = (P, R)>, P: AsRef<str>, R: Read + 'static
+    {
+        for (path, data) in files {
+            self.add_file(path.as_ref(), data)?;
+        }
+        Ok(self)
+    }
+
+    /// Adds everything under `directory`, keeping its layout.
+    /// Files are opened one at a time while writing.
+    pub fn add_directory<P: AsRef<Path>>(&mut self, directory: P) -> io::Result<&mut Self> {
+        self.add_directory_as(directory.as_ref(), "")
+    }
+
+    fn add_directory_as(&mut self, directory: &Path, prefix: &str) -> io::Result<&mut Self> {
+        for dir_entry in fs::read_dir(directory)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name().into_string().map_err(|name|
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not valid UTF-8.", name)))?;
+            let path = format!("{}{}", prefix, name);
+
+            if dir_entry.file_type()?.is_dir() {
+                self.add_directory_as(&dir_entry.path(), &format!("{}/", path))?;
+            } else {
+                self.add_file(&path, LazyFile::new(dir_entry.path()))?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Writes the archive to `dst` and returns its size.
+    /// Nothing is left at `dst` when it fails or is cancelled.
+    pub fn write(mut self, dst: &str) -> io::Result<u64> {
+        normalize(&mut self.root, self.timestamp);
+
+        let header = Header::new(&self.blowfish).into_bytes();
+        let mut out = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(dst)?);
+        writer::write_archive(&mut out, &header, &mut self.root, &self.blowfish, &self.monitor)
+            .and_then(|size| {
+                out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+                Ok(size)
+            })
+            .inspect_err(|_| {
+                let _ = fs::remove_file(dst);
+            })
+    }
+}
+
+/// Packs the directory `src` into a new archive at `dst`,
+/// see `Pk2Builder::timestamp` for `timestamp`.
+pub fn pack<P: AsRef<Path>>(src: P, dst: &str, key: &[u8], timestamp: Option<u64>) -> io::Result<u64> {
+    let mut builder = Pk2Builder::new(key)?;
+    if let Some(timestamp) = timestamp {
+        builder.timestamp(timestamp);
+    }
+    builder.add_directory(src)?;
+    builder.write(dst)
+}
+
+fn normalize(node: &mut Node, timestamp: u64) {
+    node.entry.access_date = timestamp;
+    node.entry.create_date = timestamp;
+    node.entry.modify_date = timestamp;
+    node.children.sort_by_key(|child| child.entry.name().to_lowercase());
+    for child in node.children.iter_mut() {
+        normalize(child, timestamp);
+    }
+}
+
+fn find(directory: &Node, name: &str) -> Option<usize> {
+    directory.children.iter().position(|child| child.entry.name().eq_ignore_ascii_case(name))
+}
+
+// Opening every file up front could run out of file handles.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LazyFile {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(File::open(&self.path)?);
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::fs;
+    use std::hash::{Hash, Hasher};
+    use super::{pack, Pk2Builder};
+    use crate::header::{self, Header};
+    use crate::blowfish::BlowFish;
+    use crate::archive::Archive;
+
+    #[test]
+    fn test_pack() {
+        let src = std::env::temp_dir().join(format!("pk2-pack-{}", std::process::id()));
+        let dst = format!("{}.pk2", src.to_str().unwrap());
+        fs::create_dir_all(src.join("server_dep/silkroad/textdata")).unwrap();
+        fs::write(src.join("server_dep/silkroad/textdata/itemdata.txt"), b"items").unwrap();
+        fs::write(src.join("type.txt"), b"Language = English").unwrap();
+
+        let size = pack(&src, &dst, b"169841", None).unwrap();
+        assert_eq!(size, fs::metadata(&dst).unwrap().len());
+
+        let bytes = fs::read(&dst).unwrap();
+        let header = Header::from_bytes(&bytes[..256]);
+        assert_eq!(header.checksum, header::checksum(&BlowFish::new(b"169841").unwrap()));
+
+        let archive = Archive::open_with_key(&dst, b"169841").unwrap();
+        assert_eq!(archive.extract("type.txt").unwrap().1, b"Language = English");
+        assert_eq!(archive.extract("server_dep/silkroad/textdata/itemdata.txt").unwrap().1, b"items");
+        assert!(archive.free_space_map().unwrap().regions().is_empty());
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+
+    #[test]
+    fn test_pack_is_reproducible() {
+        let src = std::env::temp_dir().join(format!("pk2-reproducible-{}", std::process::id()));
+        fs::create_dir_all(src.join("textdata")).unwrap();
+        for name in &["zeta.txt", "alpha.txt", "Mid.txt"] {
+            fs::write(src.join("textdata").join(name), name.as_bytes()).unwrap();
+        }
+        fs::write(src.join("version.txt"), b"188").unwrap();
+
+        let hash = |dst: &str| {
+            let mut hasher = DefaultHasher::new();
+            fs::read(dst).unwrap().hash(&mut hasher);
+            hasher.finish()
+        };
+        let first = format!("{}-1.pk2", src.to_str().unwrap());
+        let second = format!("{}-2.pk2", src.to_str().unwrap());
+        pack(&src, &first, b"169841", None).unwrap();
+        fs::write(src.join("version.txt"), b"188").unwrap();
+        pack(&src, &second, b"169841", None).unwrap();
+        assert_eq!(hash(&first), hash(&second));
+
+        let archive = Archive::open_with_key(&first, b"169841").unwrap();
+        let names: Vec<String> = archive.list("textdata").unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["alpha.txt", "Mid.txt", "zeta.txt"]);
+
+        pack(&src, &second, b"169841", Some(132_000_000_000_000_000)).unwrap();
+        assert_ne!(hash(&first), hash(&second));
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn test_builder_rejects_duplicates() {
+        let mut builder = Pk2Builder::new(b"169841").unwrap();
+        builder.add_file("a/b.txt", &b"b"[..]).unwrap();
+
+        assert!(builder.add_file("A/B.TXT", &b"b"[..]).is_err());
+        assert!(builder.add_file("a/b.txt/c.txt", &b"c"[..]).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::archive::Archive;
+use crate::progress::Monitor;
+use crate::{join_path, Entry, BLOCK_SIZE, DIRECTORY, FILE, EMPTY, SKIP_HEADER_SIZE};
+
+/**
+ * Something wrong with an archive. `directory` is the path of the
+ * directory the entry was found in, "" for the root.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// A block was reached a second time, through a `next_chain` or a directory entry.
+    ChainCycle { directory: String, block: u64 },
+    /// A block, or the data of a file, doesn't fit in the archive.
+    PastEof { path: String, position: u64, size: u64 },
+    /// A directory, or the `next_chain` of one of its blocks, points into the header.
+    InvalidPosition { path: String, position: u64 },
+    /// Two files share some of their data.
+    Overlap { path: String, other: String },
+    InvalidType { directory: String, offset: u64, entry_type: u8 },
+    DuplicateName { path: String },
+    UnterminatedName { directory: String, offset: u64 },
+    /// "." or ".." isn't where it belongs, at the start of the directory.
+    MissingNavigation { directory: String, name: &'static str },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::ChainCycle { directory, block } =>
+                write!(f, "{}: block {} is reached more than once", display(directory), block),
+            Problem::PastEof { path, position, size } =>
+                write!(f, "{}: {} bytes at {} are past the end of the archive", display(path), size, position),
+            Problem::InvalidPosition { path, position } =>
+                write!(f, "{}: block position {} is inside the header", display(path), position),
+            Problem::Overlap { path, other } =>
+                write!(f, "{}: data overlaps with {}", display(path), display(other)),
+            Problem::InvalidType { directory, offset, entry_type } =>
+                write!(f, "{}: entry at {} has an invalid type {}", display(directory), offset, entry_type),
+            Problem::DuplicateName { path } =>
+                write!(f, "{}: name is used more than once", display(path)),
+            Problem::UnterminatedName { directory, offset } =>
+                write!(f, "{}: name of the entry at {} isn't NUL terminated", display(directory), offset),
+            Problem::MissingNavigation { directory, name } =>
+                write!(f, "{}: \"{}\" is missing", display(directory), name),
+        }
+    }
+}
+
+fn display(path: &str) -> &str {
+    if path.is_empty() { "." } else { path }
+}
+
+/**
+ * What `Archive::check` found. The counts only cover what could be reached.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub files: usize,
+    pub directories: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Walks the archive without trusting any of it: every block is bounds
+/// checked before it's read and visited once, whatever points at it.
+pub(crate) fn check(archive: &Archive, monitor: &Monitor) -> io::Result<CheckReport> {
+    let mut checker = Checker {
+        archive,
+        monitor,
+        file_size: fs::metadata(&archive.pk2_path)?.len(),
+        visited: HashSet::new(),
+        files: Vec::new(),
+        report: CheckReport::default(),
+    };
+    checker.check_directory(archive.root.position, String::new(), true)?;
+    checker.check_overlaps();
+    Ok(checker.report)
+}
+
+struct Checker<'a> {
+    archive: &'a Archive,
+    monitor: &'a Monitor,
+    file_size: u64,
+    visited: HashSet<u64>,
+    files: Vec<(u64, u64, String)>,
+    report: CheckReport,
+}
+
+impl<'a> Checker<'a> {
+    fn check_directory(&mut self, position: u64, path: String, is_root: bool) -> io::Result<()> {
+        self.report.directories += 1;
+
+        let mut entries = Vec::new();
+        let mut current = position;
+        loop {
+            // 0 ends a chain, but a directory can't start there.
+            if current < SKIP_HEADER_SIZE {
+                self.report.problems.push(Problem::InvalidPosition { path: path.clone(), position: current });
+                break;
+            }
+            if !self.visited.insert(current) {
+                self.report.problems.push(Problem::ChainCycle { directory: path.clone(), block: current });
+                break;
+            }
+            if current.saturating_add(BLOCK_SIZE) > self.file_size {
+                self.report.problems.push(Problem::PastEof { path: path.clone(), position: current, size: BLOCK_SIZE });
+                break;
+            }
+            let block = self.archive.get_entries_of_block(current)?;
+            current = block.last().map_or(0, |entry| entry.next_chain);
+            entries.extend(block);
+            if current == 0 {
+                break;
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        if entries[0].entry_type != DIRECTORY || entries[0].name() != "." {
+            self.report.problems.push(Problem::MissingNavigation { directory: path.clone(), name: "." });
+        }
+        if !is_root && (entries[1].entry_type != DIRECTORY || entries[1].name() != "..") {
+            self.report.problems.push(Problem::MissingNavigation { directory: path.clone(), name: ".." });
+        }
+
+        let mut names: HashMap<String, usize> = HashMap::new();
+        let mut subdirectories = Vec::new();
+        for entry in entries.iter() {
+            if entry.entry_type != EMPTY && !entry.is_used() {
+                self.report.problems.push(Problem::InvalidType {
+                    directory: path.clone(), offset: entry.offset, entry_type: entry.entry_type,
+                });
+                continue;
+            }
+            if !entry.is_used() || entry.is_navigation() {
+                continue;
+            }
+            if !entry.name.contains(&0) {
+                self.report.problems.push(Problem::UnterminatedName { directory: path.clone(), offset: entry.offset });
+            }
+
+            let child = join_path(&path, &entry.name());
+            let count = names.entry(entry.name().to_lowercase()).or_insert(0);
+            *count += 1;
+            if *count == 2 {
+                self.report.problems.push(Problem::DuplicateName { path: child.clone() });
+            }
+
+            if entry.entry_type == FILE {
+                self.check_file(entry, child)?;
+            } else {
+                subdirectories.push((entry.position, child));
+            }
+        }
+
+        for (position, child) in subdirectories {
+            self.check_directory(position, child, false)?;
+        }
+        Ok(())
+    }
+
+    fn check_file(&mut self, entry: &Entry, path: String) -> io::Result<()> {
+        self.report.files += 1;
+        self.monitor.advance(entry.size as u64, &path)?;
+        if entry.position.saturating_add(entry.size as u64) > self.file_size {
+            self.report.problems.push(Problem::PastEof { path, position: entry.position, size: entry.size as u64 });
+        } else if entry.size > 0 {
+            self.files.push((entry.position, entry.position + entry.size as u64, path));
+        }
+        Ok(())
+    }
+
+    fn check_overlaps(&mut self) {
+        self.files.sort();
+        // the file reaching furthest so far, anything starting before its end overlaps it.
+        let mut furthest: Option<&(u64, u64, String)> = None;
+        for file in self.files.iter() {
+            match furthest {
+                Some(other) if file.0 < other.1 => {
+                    self.report.problems.push(Problem::Overlap { path: file.2.clone(), other: other.2.clone() });
+                    if file.1 > other.1 {
+                        furthest = Some(file);
+                    }
+                },
+                _ => furthest = Some(file),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::Problem;
+    use crate::archive::Archive;
+    use crate::tests::{create_empty_archive, write_entry};
+
+    #[test]
+    fn test_check_clean_archive() {
+        let path = create_empty_archive("check-clean");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a/b/c.txt", b"c").unwrap();
+        archive.writer().unwrap().add("a/d.txt", b"d").unwrap();
+
+        let report = archive.check().unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.files, report.directories), (2, 3));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_check_finds_problems() {
+        let path = create_empty_archive("check-broken");
+        let archive = Archive::open_rw(&path).unwrap();
+        for name in &["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"] {
+            archive.writer().unwrap().add(&format!("dir/{}", name), name.as_bytes()).unwrap();
+        }
+        let entry = |name: &str| archive.extract(&format!("dir/{}", name)).unwrap().0;
+        let (a, mut b, mut c, mut d, mut e) = (entry("a.txt"), entry("b.txt"), entry("c.txt"), entry("d.txt"), entry("e.txt"));
+
+        b.set_name("A.TXT").unwrap();
+        write_entry(&archive, b);
+        c.position = a.position;
+        write_entry(&archive, c);
+        d.size = u32::MAX;
+        write_entry(&archive, d);
+        e.entry_type = 7;
+        write_entry(&archive, e);
+        let mut last = archive.get_entries_of_block(a.offset - 2 * 128).unwrap()[19];
+        last.next_chain = a.offset - 2 * 128;
+        write_entry(&archive, last);
+
+        let problems = archive.check().unwrap().problems;
+        assert!(problems.contains(&Problem::DuplicateName { path: "dir/A.TXT".to_string() }));
+        assert!(problems.contains(&Problem::Overlap { path: "dir/c.txt".to_string(), other: "dir/a.txt".to_string() }));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::PastEof { path, .. } if path == "dir/d.txt")));
+        assert!(problems.contains(&Problem::InvalidType { directory: "dir".to_string(), offset: e.offset, entry_type: 7 }));
+        assert!(problems.contains(&Problem::ChainCycle { directory: "dir".to_string(), block: a.offset - 2 * 128 }));
+        assert_eq!(problems.len(), 5);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_check_directory_at_zero() {
+        let path = create_empty_archive("check-zero");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("dir/a.txt", b"a").unwrap();
+        let mut dir = archive.list(".").unwrap().into_iter().find(|entry| entry.name() == "dir").unwrap();
+        dir.position = 0;
+        write_entry(&archive, dir);
+
+        let problems = archive.check().unwrap().problems;
+        assert_eq!(problems, vec![Problem::InvalidPosition { path: "dir".to_string(), position: 0 }]);
+        fs::remove_file(path).unwrap();
+    }
+}
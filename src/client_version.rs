@@ -0,0 +1,115 @@
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BufMut};
+
+use crate::blowfish::{BlowFish, Padding};
+
+/// Where Media.pk2 keeps the client version.
+pub const SV_T_PATH: &str = "SV.T";
+// Only the first 8 bytes of the key are used.
+const SV_T_KEY: &[u8] = b"SILKROADVERSION";
+const SV_T_KEY_LENGTH: usize = 8;
+
+/**
+ * The version number in `SV.T`: the length of the encrypted part (4 Byte),
+ * then the number as ASCII digits, NUL padded to whole blocks and encrypted
+ * with JoyMax's Blowfish. Anything after the encrypted part is kept as it is.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientVersion(pub u32);
+
+impl ClientVersion {
+    pub fn from_bytes(mut buffer: &[u8]) -> io::Result<Self> {
+        let encrypted = encrypted_part(&mut buffer)?;
+        let decrypted = blowfish().decrypt_padded(encrypted, Padding::None)?;
+        let digits = decrypted.split(|byte| *byte == 0).next().unwrap_or(&[]);
+        std::str::from_utf8(digits).ok().and_then(|digits| digits.parse().ok()).map(ClientVersion).ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidData, format!("{} doesn't hold a version number.", SV_T_PATH)))
+    }
+
+    /// `original` with the version replaced, a fresh file without one.
+    pub fn into_bytes(self, original: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let encrypted = blowfish().encrypt_padded(self.0.to_string().as_bytes(), Padding::Zero)?;
+        let rest = match original {
+            Some(mut original) => {
+                encrypted_part(&mut original)?;
+                original
+            },
+            None => &[],
+        };
+
+        let mut buffer = Vec::with_capacity(4 + encrypted.len() + rest.len());
+        buffer.put_u32_le(encrypted.len() as u32);
+        buffer.put_slice(&encrypted);
+        buffer.put_slice(rest);
+        Ok(buffer)
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn blowfish() -> BlowFish {
+    BlowFish::with_key_range(SV_T_KEY, 0, SV_T_KEY_LENGTH).expect("the key is 15 bytes")
+}
+
+// Takes the length prefix and the encrypted part off `buffer`.
+fn encrypted_part<'a>(buffer: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{} is cut short.", SV_T_PATH));
+    if buffer.remaining() < 4 {
+        return Err(invalid());
+    }
+    let length = buffer.get_u32_le() as usize;
+    if buffer.len() < length {
+        return Err(invalid());
+    }
+    let (encrypted, rest) = buffer.split_at(length);
+    *buffer = rest;
+    Ok(encrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{ClientVersion, SV_T_PATH};
+    use crate::archive::Archive;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_client_version() {
+        let bytes = ClientVersion(188).into_bytes(None).unwrap();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(&bytes[..4], &[8, 0, 0, 0]);
+        assert_eq!(ClientVersion::from_bytes(&bytes).unwrap(), ClientVersion(188));
+        assert!(ClientVersion::from_bytes(&bytes[..10]).is_err());
+
+        // whatever follows the version survives a bump.
+        let mut original = bytes;
+        original.extend_from_slice(b"tail");
+        let path = create_empty_archive("client-version");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add(SV_T_PATH, &original).unwrap();
+        assert_eq!(archive.client_version().unwrap(), ClientVersion(188));
+
+        assert_eq!(archive.writer().unwrap().bump_client_version().unwrap(), ClientVersion(189));
+        archive.writer().unwrap().set_client_version(ClientVersion(12345678)).unwrap();
+        assert_eq!(archive.client_version().unwrap().to_string(), "12345678");
+        let bytes = archive.extract("sv.t").unwrap().1;
+        assert_eq!(&bytes[bytes.len() - 4..], b"tail");
+
+        archive.writer().unwrap().set_client_version(ClientVersion(u32::MAX)).unwrap();
+        let err = archive.writer().unwrap().bump_client_version().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(archive.client_version().unwrap(), ClientVersion(u32::MAX));
+
+        archive.writer().unwrap().delete(SV_T_PATH).unwrap();
+        assert!(archive.client_version().is_err());
+        archive.writer().unwrap().set_client_version(ClientVersion(7)).unwrap();
+        assert_eq!(archive.client_version().unwrap(), ClientVersion(7));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::writer::{self, Node};
+use crate::archive::{Archive, Mode};
+use crate::history;
+use crate::options::OpenOptions;
+use crate::progress::Monitor;
+use crate::{sync_directory, Entry, DIRECTORY};
+
+/**
+ * What a compaction got rid of: holes, old data left behind by `patch`,
+ * deleted entries and half empty entry blocks.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactReport {
+    pub original_size: u64,
+    pub compacted_size: u64,
+}
+
+impl CompactReport {
+    pub fn reclaimed(&self) -> u64 {
+        self.original_size.saturating_sub(self.compacted_size)
+    }
+}
+
+/// Rewrites `src` into `dst` with its entry blocks and file data packed
+/// back to back. Names, dates, the header and so the key are kept as they
+/// are, `sort` orders every directory by name instead of keeping the
+/// order of the original.
+pub fn compact(src: &str, dst: &str, sort: bool) -> io::Result<CompactReport> {
+    compact_with_monitor(src, dst, sort, &OpenOptions::new(), &Monitor::default())
+}
+
+/// `compact` for an archive opened with `options`, reporting every file
+/// copied to `monitor`. `src` is only read whatever the options' mode.
+/// Nothing is left at `dst` when it fails or is cancelled.
+pub fn compact_with_monitor(src: &str, dst: &str, sort: bool,
+                            options: &OpenOptions, monitor: &Monitor) -> io::Result<CompactReport> {
+    check_distinct(src, dst)?;
+    let archive = options.clone().mode(Mode::ReadOnly).open(src)?;
+    let source = Rc::new(File::open(src)?);
+
+    let mut visited = HashSet::new();
+    let children = read_tree(&archive, &archive.root, &source, sort, &mut visited)?;
+    let mut root = Node::directory(archive.root, children);
+
+    let header = archive.header()?.into_bytes();
+    let mut out = BufWriter::new(fs::OpenOptions::new().write(true).create(true).truncate(true).open(dst)?);
+    let compacted_size = writer::write_archive(&mut out, &header, &mut root, &archive.blowfish, monitor)
+        .and_then(|size| {
+            out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+            Ok(size)
+        })
+        .inspect_err(|_| {
+            let _ = fs::remove_file(dst);
+        })?;
+
+    Ok(CompactReport {
+        original_size: fs::metadata(src)?.len(),
+        compacted_size,
+    })
+}
+
+/// `compact` through a temporary file next to `path`, which then replaces
+/// it, so the archive is either the old one or the compacted one.
+pub fn compact_in_place(path: &str, sort: bool) -> io::Result<CompactReport> {
+    compact_in_place_with_monitor(path, sort, &OpenOptions::new(), &Monitor::default())
+}
+
+pub fn compact_in_place_with_monitor(path: &str, sort: bool,
+                                     options: &OpenOptions, monitor: &Monitor) -> io::Result<CompactReport> {
+    let file_name = Path::new(path).file_name().and_then(|name| name.to_str()).ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid archive path: {}.", path)))?;
+    let temp = Path::new(path).with_file_name(format!(".{}.compact", file_name));
+    let temp = temp.to_str().unwrap();
+
+    // kept until the compacted archive took its place.
+    let archive = options.clone().mode(Mode::ReadWrite).open(path)?;
+    let _writer = archive.writer()?;
+
+    let report = compact_with_monitor(path, temp, sort, options, monitor)?;
+    // the old versions didn't make it into the compacted archive, their offsets
+    // mean nothing in it. Forgotten first, a crash before the rename only loses them.
+    history::clear(path)?;
+    fs::rename(temp, path)?;
+    sync_directory(path)?;
+    Ok(report)
+}
+
+/// Fails when `dst` is `src` under another path or a hard link to it,
+/// truncating it for the output would destroy the input.
+pub(crate) fn check_distinct(src: &str, dst: &str) -> io::Result<()> {
+    let dst_metadata = match fs::metadata(dst) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let same = same_file(&fs::metadata(src)?, &dst_metadata)
+        || fs::canonicalize(src)? == fs::canonicalize(dst)?;
+    if same {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} and {} are the same file.", src, dst)));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// Only the paths are compared, hard links go unnoticed.
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+fn read_tree(archive: &Archive, directory: &Entry, source: &Rc<File>,
+             sort: bool, visited: &mut HashSet<u64>) -> io::Result<Vec<Node>> {
+    // a directory reachable twice would be written twice, keep the first.
+    if !visited.insert(directory.position) {
+        return Ok(Vec::new());
+    }
+
+    let mut children = archive.read_children(directory)?;
+    if sort {
+        children.sort_by_key(|child| child.name().to_lowercase());
+    }
+
+    children.into_iter().map(|child| {
+        Ok(if child.entry_type == DIRECTORY {
+            Node::directory(child, read_tree(archive, &child, source, sort, visited)?)
+        } else {
+            Node::file(child, Box::new(ArchiveSlice::new(source.clone(), child.position, child.size as u64)))
+        })
+    }).collect()
+}
+
+/**
+ * Reads one file's data out of the source archive. All of them share a single
+ * handle, which is fine since the writer reads them one after the other.
+ */
+pub(crate) struct ArchiveSlice {
+    file: Rc<File>,
+    position: u64,
+    remaining: u64,
+}
+
+impl ArchiveSlice {
+    pub(crate) fn new(file: Rc<File>, position: u64, size: u64) -> Self {
+        Self { file, position, remaining: size }
+    }
+}
+
+impl Read for ArchiveSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let mut file = &*self.file;
+        file.seek(SeekFrom::Start(self.position))?;
+        let count = buf.len().min(self.remaining as usize);
+        let read = file.read(&mut buf[..count])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File data goes past the end of the archive."));
+        }
+        self.position += read as u64;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{compact, compact_in_place, compact_with_monitor};
+    use crate::archive::Archive;
+    use crate::options::OpenOptions;
+    use crate::progress::Monitor;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_compact() {
+        let path = create_empty_archive("compact-src");
+        let dst = path.replace("compact-src", "compact-dst");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("textdata/b.txt", &[1; 500]).unwrap();
+        archive.writer().unwrap().add("textdata/a.txt", &[2; 500]).unwrap();
+        archive.writer().unwrap().add("gone.txt", &[3; 500]).unwrap();
+        archive.writer().unwrap().patch("textdata/b.txt", &[4; 700]).unwrap();
+        archive.writer().unwrap().delete("gone.txt").unwrap();
+
+        let report = compact(&path, &dst, true).unwrap();
+        assert_eq!(report.original_size, fs::metadata(&path).unwrap().len());
+        assert_eq!(report.compacted_size, fs::metadata(&dst).unwrap().len());
+        assert_eq!(report.compacted_size, 256 + 2 * 2560 + 1200);
+
+        let compacted = Archive::open(&dst).unwrap();
+        let names: Vec<String> = compacted.list("textdata").unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["a.txt", "b.txt"]);
+        assert_eq!(compacted.extract("textdata/b.txt").unwrap().1, vec![4; 700]);
+        assert!(compacted.free_space_map().unwrap().regions().is_empty());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+
+    #[test]
+    fn test_compact_in_place() {
+        let path = create_empty_archive("compact-in-place");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", &[1; 100]).unwrap();
+        archive.writer().unwrap().patch("a.txt", &[2; 100]).unwrap();
+
+        let report = compact_in_place(&path, false).unwrap();
+        assert_eq!(report.reclaimed(), 100);
+        assert_eq!(archive.extract("a.txt").unwrap().1, vec![2; 100]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_onto_itself() {
+        let path = create_empty_archive("compact-itself");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", &[1; 100]).unwrap();
+        drop(archive);
+        let before = fs::read(&path).unwrap();
+
+        let file_name = std::path::Path::new(&path).file_name().unwrap().to_str().unwrap();
+        let other_path = std::env::temp_dir().join(".").join(file_name);
+        for dst in [path.as_str(), other_path.to_str().unwrap()] {
+            let err = compact(&path, dst, false).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(fs::read(&path).unwrap(), before);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_with_key() {
+        let path = create_empty_archive("compact-key-src");
+        let dst = path.replace("compact-key-src", "compact-key-dst");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", &[1; 100]).unwrap();
+        archive.writer().unwrap().patch("a.txt", &[2; 100]).unwrap();
+        drop(archive.rekey(b"169841").unwrap());
+
+        assert!(compact(&path, &dst, false).is_err());
+        let mut options = OpenOptions::new();
+        options.key(b"169841");
+        let report = compact_with_monitor(&path, &dst, false, &options, &Monitor::default()).unwrap();
+        assert_eq!(report.reclaimed(), 100);
+        assert_eq!(options.open(&dst).unwrap().extract("a.txt").unwrap().1, vec![2; 100]);
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::archive::Archive;
+use crate::progress::Monitor;
+use crate::{is_plain_name, join_path, thread_count, Entry, DIRECTORY, FILE, FILETIME_UNIX_EPOCH};
+
+/**
+ * Settings for `Archive::extract_all`. Filters are matched against the path
+ * below the extracted directory, case-insensitively, `*` matches any run of
+ * characters, `/` included, and `?` any single one. A file is extracted when
+ * it matches one of `include` (or there is none) and none of `exclude`.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    threads: usize,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    monitor: Monitor,
+}
+
+impl ExtractOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many files are extracted at once, 0 (the default) uses every core.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn include(&mut self, pattern: &str) -> &mut Self {
+        self.include.push(pattern.to_lowercase());
+        self
+    }
+
+    /// Excluded directories aren't created and nothing below them is extracted.
+    pub fn exclude(&mut self, pattern: &str) -> &mut Self {
+        self.exclude.push(pattern.to_lowercase());
+        self
+    }
+
+    /// Gets every extracted file, a cancelled extraction keeps the files written so far.
+    pub fn monitor(&mut self, monitor: Monitor) -> &mut Self {
+        self.monitor = monitor;
+        self
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        let path = path.to_lowercase();
+        self.exclude.iter().any(|pattern| matches(pattern.as_bytes(), path.as_bytes()))
+    }
+
+    fn is_included(&self, path: &str) -> bool {
+        let path = path.to_lowercase();
+        self.include.is_empty() || self.include.iter().any(|pattern| matches(pattern.as_bytes(), path.as_bytes()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtractReport {
+    pub files: usize,
+    pub directories: usize,
+    pub bytes: u64,
+}
+
+/// Recreates `source` (a directory, or a single file) under `destination`.
+/// Files get the modify date of their entry as mtime, directories too
+/// once everything in them is written.
+pub(crate) fn extract_all(archive: &Archive, source: &Entry, destination: &Path,
+                          options: &ExtractOptions) -> io::Result<ExtractReport> {
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    if source.entry_type == FILE {
+        files.push((source.name(), destination.join(extracted_name(source)?), *source));
+    } else {
+        let mut visited = HashSet::new();
+        collect(archive, source, "", destination, options, &mut visited, &mut files, &mut directories)?;
+    }
+
+    fs::create_dir_all(destination)?;
+    for (path, _) in directories.iter() {
+        fs::create_dir_all(path)?;
+    }
+
+    let threads = thread_count(options.threads);
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let error = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let (name, path, entry) = match files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        Some(file) => file,
+                        None => break,
+                    };
+                    let result = options.monitor.check()
+                        .and_then(|_| extract_file(archive, entry, path))
+                        .and_then(|_| options.monitor.advance(entry.size as u64, name));
+                    if let Err(err) = result {
+                        failed.store(true, Ordering::Relaxed);
+                        error.lock().unwrap().get_or_insert(err);
+                    }
+                }
+            });
+        }
+    });
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+
+    // deepest first, setting a directory's mtime doesn't touch its parent's.
+    for (path, entry) in directories.iter().rev() {
+        set_modified(path, entry)?;
+    }
+
+    Ok(ExtractReport {
+        files: files.len(),
+        directories: directories.len(),
+        bytes: files.iter().map(|(_, _, entry)| entry.size as u64).sum(),
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect(archive: &Archive, directory: &Entry, path: &str, destination: &Path, options: &ExtractOptions,
+           visited: &mut HashSet<u64>, files: &mut Vec<(String, PathBuf, Entry)>,
+           directories: &mut Vec<(PathBuf, Entry)>) -> io::Result<()> {
+    if !visited.insert(directory.position) {
+        return Ok(());
+    }
+    for child in archive.read_children(directory)? {
+        let child_path = join_path(path, &child.name());
+        if options.is_excluded(&child_path) {
+            continue;
+        }
+        let target = destination.join(extracted_name(&child)?);
+        if child.entry_type == DIRECTORY {
+            directories.push((target.clone(), child));
+            collect(archive, &child, &child_path, &target, options, visited, files, directories)?;
+        } else if child.entry_type == FILE && options.is_included(&child_path) {
+            files.push((child_path, target, child));
+        }
+    }
+    Ok(())
+}
+
+// Names come from the archive, one that would land outside `destination` fails the extraction.
+fn extracted_name(entry: &Entry) -> io::Result<String> {
+    let name = entry.name();
+    if !is_plain_name(&name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("The entry at {} is named {:?}, it can't be extracted.", entry.offset, name)));
+    }
+    Ok(name)
+}
+
+fn extract_file(archive: &Archive, entry: &Entry, path: &Path) -> io::Result<()> {
+    fs::write(path, archive.read_bytes(entry.position, entry.size)?)?;
+    set_modified(path, entry)
+}
+
+fn set_modified(path: &Path, entry: &Entry) -> io::Result<()> {
+    // entries written without a date are left with the current time.
+    if entry.modify_date <= FILETIME_UNIX_EPOCH {
+        return Ok(());
+    }
+    let since_epoch = Duration::from_nanos((entry.modify_date - FILETIME_UNIX_EPOCH).saturating_mul(100));
+    let modified: SystemTime = UNIX_EPOCH + since_epoch;
+    open_for_times(path)?.set_modified(modified)
+}
+
+// Windows only opens a directory with FILE_FLAG_BACKUP_SEMANTICS, and setting
+// times needs FILE_WRITE_ATTRIBUTES, which reading doesn't grant.
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    File::options().access_mode(FILE_WRITE_ATTRIBUTES).custom_flags(FILE_FLAG_BACKUP_SEMANTICS).open(path)
+}
+
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    File::options().write(!path.is_dir()).read(path.is_dir()).open(path)
+}
+
+// Iterative wildcard matching, backtracking to the last `*` on a mismatch.
+fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|chr| *chr == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{matches, ExtractOptions};
+    use crate::archive::Archive;
+    use crate::builder::pack;
+    use crate::tests::{create_empty_archive, write_entry};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(b"*.txt", b"textdata/item.txt"));
+        assert!(matches(b"textdata/*", b"textdata/a/b.ddj"));
+        assert!(matches(b"?.txt", b"a.txt"));
+        assert!(!matches(b"*.txt", b"item.ddj"));
+        assert!(!matches(b"?.txt", b"ab.txt"));
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let src = std::env::temp_dir().join(format!("pk2-extract-all-{}", std::process::id()));
+        let dst = src.with_extension("out");
+        let pk2 = format!("{}.pk2", src.to_str().unwrap());
+        fs::create_dir_all(src.join("data/textdata")).unwrap();
+        fs::create_dir_all(src.join("data/icon")).unwrap();
+        for i in 0..10 {
+            fs::write(src.join(format!("data/textdata/{}.txt", i)), vec![i as u8; i * 10]).unwrap();
+        }
+        fs::write(src.join("data/textdata/skip.ddj"), b"ddj").unwrap();
+        fs::write(src.join("data/icon/a.ddj"), b"icon").unwrap();
+        // 2020-01-01
+        pack(&src, &pk2, crate::PK2_KEYS, Some(132_223_104_000_000_000)).unwrap();
+
+        let archive = Archive::open(&pk2).unwrap();
+        let mut options = ExtractOptions::new();
+        options.threads(3).include("*.txt").exclude("icon");
+        let report = archive.extract_all("data", &dst, &options).unwrap();
+
+        assert_eq!((report.files, report.directories, report.bytes), (10, 1, 450));
+        assert_eq!(fs::read(dst.join("textdata/7.txt")).unwrap(), vec![7; 70]);
+        assert!(!dst.join("textdata/skip.ddj").exists());
+        assert!(!dst.join("icon").exists());
+        let modified = fs::metadata(dst.join("textdata/3.txt")).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_577_836_800));
+        let modified = fs::metadata(dst.join("textdata")).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_577_836_800));
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(dst).unwrap();
+        fs::remove_file(pk2).unwrap();
+    }
+
+    #[test]
+    fn test_extract_all_rejects_escaping_names() {
+        let path = create_empty_archive("extract-escape");
+        let dst = std::env::temp_dir().join(format!("pk2-extract-escape-{}", std::process::id())).join("out");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("dir/a.txt", b"a").unwrap();
+        let mut entry = archive.extract("dir/a.txt").unwrap().0;
+
+        for name in ["../evil.txt", "..", "..\\evil.txt", "/tmp/evil.txt"] {
+            entry.set_name(name).unwrap();
+            write_entry(&archive, entry);
+            let err = archive.extract_all("dir", &dst, &ExtractOptions::new()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        assert!(!dst.parent().unwrap().join("evil.txt").exists());
+        assert!(!dst.exists());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use bytes::{Buf, BufMut};
+use std::io;
+
+use crate::blowfish::BlowFish;
+use crate::SKIP_HEADER_SIZE;
+
+pub const SIGNATURE: &[u8] = b"JoyMax File Manager!\n";
+pub const VERSION: u32 = 0x0100_0002;
+// Encrypted with the archive's key, only the first 3 bytes are kept.
+pub const CHECKSUM_PLAINTEXT: &[u8; 16] = b"Joymax Pak File\0";
+
+/**
+ * The first 256 bytes of every archive.
+ */
+#[derive(Clone, Copy)]
+pub struct Header {
+    pub signature: [u8; 30],    // 30 Byte;
+    pub version: u32,           // 4 Byte;
+    pub encrypted: u8,          // 1 Byte; 1 when the entries are encrypted
+    pub checksum: [u8; 16],     // 16 Byte;
+    pub reserved: [u8; 205],    // 205 Byte, unused
+}
+
+impl Header {
+    /// A stock header for an archive encrypted with `blowfish`.
+    pub fn new(blowfish: &BlowFish) -> Self {
+        let mut signature = [0; 30];
+        signature[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        Self {
+            signature,
+            version: VERSION,
+            encrypted: 1,
+            checksum: checksum(blowfish),
+            reserved: [0; 205],
+        }
+    }
+
+    pub fn from_bytes(mut buffer: &[u8]) -> Self {
+        let mut header = Self {
+            signature: [0; 30],
+            version: 0,
+            encrypted: 0,
+            checksum: [0; 16],
+            reserved: [0; 205],
+        };
+        buffer.copy_to_slice(&mut header.signature);
+        header.version = buffer.get_u32_le();
+        header.encrypted = buffer.get_u8();
+        buffer.copy_to_slice(&mut header.checksum);
+        buffer.copy_to_slice(&mut header.reserved);
+        header
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(SKIP_HEADER_SIZE as usize);
+        buffer.put_slice(&self.signature);
+        buffer.put_u32_le(self.version);
+        buffer.put_u8(self.encrypted);
+        buffer.put_slice(&self.checksum);
+        buffer.put_slice(&self.reserved);
+        buffer
+    }
+}
+
+pub fn checksum(blowfish: &BlowFish) -> [u8; 16] {
+    checksum_with(blowfish, CHECKSUM_PLAINTEXT).expect("16 bytes are two blocks")
+}
+
+/// The checksum of modified clients that encrypt something else.
+pub fn checksum_with(blowfish: &BlowFish, plaintext: &[u8]) -> io::Result<[u8; 16]> {
+    if plaintext.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The checksum plaintext is empty."));
+    }
+    let mut encrypted = plaintext.to_vec();
+    blowfish.encrypt_in_place(&mut encrypted)?;
+    let mut checksum = [0; 16];
+    checksum[..3].copy_from_slice(&encrypted[..3]);
+    Ok(checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+    use crate::blowfish::BlowFish;
+    use crate::PK2_KEYS;
+
+    #[test]
+    fn test_header_conversion() {
+        let blowfish = BlowFish::new(PK2_KEYS).unwrap();
+        let bytes = Header::new(&blowfish).into_bytes();
+        let header = Header::from_bytes(&bytes);
+
+        assert_eq!(bytes.len(), 256);
+        assert_eq!(&bytes[..21], b"JoyMax File Manager!\n");
+        assert_eq!(header.version, 0x0100_0002);
+        assert_eq!(header.into_bytes(), bytes);
+    }
+}
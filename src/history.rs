@@ -0,0 +1,144 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use bytes::{Buf, BufMut};
+
+use crate::split_path;
+
+/**
+ * A version of a file that `patch` replaced. Its data is still in the
+ * archive at `position`, the history keeps it from being handed out again.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub path: String,
+    pub position: u64,
+    pub size: u32,
+    pub replaced: u64,  // FILETIME of the patch that replaced it
+}
+
+/*
+ * Versions are appended to `<archive>.history`, which only exists once
+ * `Archive::enable_history` created it. Every record is the path's length
+ * (2 Byte), the path, position (8 Byte), size (4 Byte) and replaced (8 Byte).
+ */
+pub(crate) fn history_path(pk2_path: &str) -> String {
+    format!("{}.history", pk2_path)
+}
+
+pub(crate) fn is_enabled(pk2_path: &str) -> bool {
+    Path::new(&history_path(pk2_path)).exists()
+}
+
+pub(crate) fn enable(pk2_path: &str) -> io::Result<()> {
+    OpenOptions::new().append(true).create(true).open(history_path(pk2_path)).map(|_| ())
+}
+
+/// The same path however it's written: no empty parts and no case.
+pub(crate) fn normalize(path: &str) -> String {
+    split_path(path).join("/").to_lowercase()
+}
+
+/// Every version recorded so far, oldest first.
+pub(crate) fn read(pk2_path: &str) -> io::Result<Vec<Version>> {
+    let buffer = match fs::read(history_path(pk2_path)) {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut versions = Vec::new();
+    let mut cursor = &buffer[..];
+    // a record cut short by a crash is dropped.
+    while cursor.remaining() >= 2 {
+        let length = cursor.get_u16_le() as usize;
+        if cursor.remaining() < length + 20 {
+            break;
+        }
+        let path = String::from_utf8_lossy(&cursor[..length]).into_owned();
+        cursor.advance(length);
+        versions.push(Version {
+            path,
+            position: cursor.get_u64_le(),
+            size: cursor.get_u32_le(),
+            replaced: cursor.get_u64_le(),
+        });
+    }
+    Ok(versions)
+}
+
+/// Records `versions` if the archive keeps a history, does nothing otherwise.
+pub(crate) fn append(pk2_path: &str, versions: &[Version]) -> io::Result<()> {
+    if versions.is_empty() || !is_enabled(pk2_path) {
+        return Ok(());
+    }
+
+    let mut buffer = Vec::new();
+    for version in versions {
+        buffer.put_u16_le(version.path.len() as u16);
+        buffer.put_slice(version.path.as_bytes());
+        buffer.put_u64_le(version.position);
+        buffer.put_u32_le(version.size);
+        buffer.put_u64_le(version.replaced);
+    }
+
+    let mut file = OpenOptions::new().append(true).open(history_path(pk2_path))?;
+    file.write_all(&buffer)?;
+    file.sync_data()
+}
+
+/// Forgets every version but keeps the history enabled.
+pub(crate) fn clear(pk2_path: &str) -> io::Result<()> {
+    if is_enabled(pk2_path) {
+        fs::write(history_path(pk2_path), [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::history_path;
+    use crate::archive::Archive;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_revert() {
+        let path = create_empty_archive("history");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.enable_history().unwrap();
+        archive.writer().unwrap().add("textdata/item.txt", b"first").unwrap();
+        archive.writer().unwrap().patch("textdata/item.txt", b"second").unwrap();
+        archive.writer().unwrap().patch("TextData/Item.txt", b"third").unwrap();
+
+        let versions = archive.history("textdata/item.txt").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].size, 5);
+        // the old versions aren't free space any more.
+        assert!(archive.free_space_map().unwrap().regions().is_empty());
+        archive.writer().unwrap().add("other.txt", b"other").unwrap();
+
+        archive.writer().unwrap().revert("textdata/item.txt", 0).unwrap();
+        assert_eq!(archive.extract("textdata/item.txt").unwrap().1, b"first");
+        assert_eq!(archive.history("textdata/item.txt").unwrap().len(), 3);
+        archive.writer().unwrap().revert("textdata/item.txt", 2).unwrap();
+        assert_eq!(archive.extract("textdata/item.txt").unwrap().1, b"third");
+        assert!(archive.writer().unwrap().revert("textdata/item.txt", 9).is_err());
+
+        fs::remove_file(history_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_no_history_unless_enabled() {
+        let path = create_empty_archive("no-history");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        archive.writer().unwrap().patch("a.txt", b"b").unwrap();
+
+        assert!(archive.history("a.txt").unwrap().is_empty());
+        assert!(fs::metadata(history_path(&path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
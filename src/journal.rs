@@ -0,0 +1,188 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write, Seek, SeekFrom};
+
+use std::path::Path;
+
+use bytes::{Buf, BufMut};
+
+use crate::lock::FileLock;
+use crate::sync_directory;
+
+/**
+ * The entry writes of a commit are logged into `<archive>.journal` and
+ * synced before any of them touch the archive. A crash before the journal
+ * is complete leaves the archive untouched and the journal is thrown away,
+ * a crash after it leaves a journal that is replayed on the next open.
+ *
+ * Layout: "PK2J", the number of writes, every write as offset (8 Byte),
+ * length (4 Byte) and bytes, then an FNV-1a hash of everything before it.
+ */
+const MAGIC: &[u8; 4] = b"PK2J";
+
+pub(crate) fn journal_path(pk2_path: &str) -> String {
+    format!("{}.journal", pk2_path)
+}
+
+pub(crate) fn write(pk2_path: &str, writes: &[(u64, Vec<u8>)]) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.put_slice(MAGIC);
+    buffer.put_u32_le(writes.len() as u32);
+    for (offset, bytes) in writes {
+        buffer.put_u64_le(*offset);
+        buffer.put_u32_le(bytes.len() as u32);
+        buffer.put_slice(bytes);
+    }
+    let hash = fnv1a(&buffer);
+    buffer.put_u64_le(hash);
+
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(journal_path(pk2_path))?;
+    file.write_all(&buffer)?;
+    file.sync_all()?;
+    // a freshly created journal is only sure to be found after a crash
+    // once the directory listing it is on disk too.
+    sync_directory(pk2_path)
+}
+
+pub(crate) fn clear(pk2_path: &str) -> io::Result<()> {
+    match fs::remove_file(journal_path(pk2_path)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Finishes an interrupted commit if its journal made it to disk whole,
+/// returns whether anything was replayed. A journal is left alone while
+/// a writer holds the archive, its commit is still going on.
+pub(crate) fn recover(pk2_path: &str) -> io::Result<bool> {
+    if !Path::new(&journal_path(pk2_path)).exists() {
+        return Ok(false);
+    }
+    let _lock = match FileLock::try_exclusive(pk2_path) {
+        Ok(lock) => lock,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    let buffer = match fs::read(journal_path(pk2_path)) {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    let writes = match parse(&buffer) {
+        Some(writes) => writes,
+        None => {
+            clear(pk2_path)?;
+            return Ok(false);
+        }
+    };
+
+    let mut writer = BufWriter::new(OpenOptions::new().write(true).open(pk2_path)?);
+    for (offset, bytes) in writes {
+        writer.seek(SeekFrom::Start(offset))?;
+        writer.write_all(bytes)?;
+    }
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    clear(pk2_path)?;
+    Ok(true)
+}
+
+/// For a read-only open, which can't replay: fails while a whole journal
+/// is waiting, the archive holds only part of that commit.
+pub(crate) fn check_finished(pk2_path: &str) -> io::Result<()> {
+    let buffer = match fs::read(journal_path(pk2_path)) {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    // a partial journal never touched the archive, an empty one has nothing to finish.
+    if parse(&buffer).is_none_or(|writes| writes.is_empty()) {
+        return Ok(());
+    }
+    // `WouldBlock` while a writer holds the archive, its commit is still going on.
+    FileLock::try_exclusive(pk2_path)?;
+    Err(io::Error::new(io::ErrorKind::InvalidData,
+        format!("{} has an interrupted commit, open it read-write once to finish it.", pk2_path)))
+}
+
+fn parse(buffer: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    if buffer.len() < MAGIC.len() + 4 + 8 || &buffer[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let (body, mut hash) = buffer.split_at(buffer.len() - 8);
+    if fnv1a(body) != hash.get_u64_le() {
+        return None;
+    }
+
+    let mut cursor = &body[MAGIC.len()..];
+    let count = cursor.get_u32_le();
+    let mut writes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if cursor.remaining() < 12 {
+            return None;
+        }
+        let offset = cursor.get_u64_le();
+        let length = cursor.get_u32_le() as usize;
+        if cursor.remaining() < length {
+            return None;
+        }
+        writes.push((offset, &cursor[..length]));
+        cursor.advance(length);
+    }
+    Some(writes)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{journal_path, write};
+    use crate::archive::Archive;
+    use crate::tests::{create_empty_archive, encrypt_entry};
+
+    #[test]
+    fn test_replay_complete_journal() {
+        let path = create_empty_archive("journal-replay");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        assert!(fs::metadata(journal_path(&path)).is_err());
+
+        // a commit that died right after its journal was synced.
+        let entry = archive.extract("a.txt").unwrap().0;
+        let mut renamed = entry;
+        renamed.set_name("b.txt").unwrap();
+        write(&path, &[(entry.offset, encrypt_entry(&archive, renamed))]).unwrap();
+
+        let err = Archive::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let archive = Archive::open_rw(&path).unwrap();
+        assert_eq!(archive.extract("b.txt").unwrap().1, b"a");
+        assert!(Archive::open(&path).is_ok());
+        assert!(fs::metadata(journal_path(&path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_discard_partial_journal() {
+        let path = create_empty_archive("journal-partial");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        write(&path, &[(256, vec![0; 128])]).unwrap();
+        let journal = fs::read(journal_path(&path)).unwrap();
+        fs::write(journal_path(&path), &journal[..journal.len() - 3]).unwrap();
+
+        assert!(Archive::open(&path).is_ok());
+        let archive = Archive::open_rw(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert!(archive.extract("a.txt").is_ok());
+        assert!(fs::metadata(journal_path(&path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
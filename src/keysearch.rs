@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::blowfish::BlowFish;
+use crate::header::{self, Header};
+use crate::{thread_count, Entry, DIRECTORY, ENTRY_SIZE, SKIP_HEADER_SIZE};
+
+/**
+ * The keys `find_key` tries: each line of a wordlist, or every number
+ * of `min` to `max` digits, zero padded, "000" to "999" for 3.
+ */
+#[derive(Clone, Debug)]
+pub enum Candidates {
+    Words(Vec<Vec<u8>>),
+    Digits { min: usize, max: usize },
+}
+
+impl Candidates {
+    /// One candidate per line, "\r\n" endings and empty lines are skipped.
+    pub fn from_wordlist(text: &[u8]) -> Self {
+        let words = text.split(|byte| *byte == b'\n')
+                        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                        .filter(|line| !line.is_empty())
+                        .map(|line| line.to_vec())
+                        .collect();
+        Candidates::Words(words)
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Candidates::Words(words) => words.len() as u64,
+            Candidates::Digits { min, max } => (*min..=*max).map(|digits| 10u64.saturating_pow(digits as u32)).sum(),
+        }
+    }
+
+    fn get(&self, mut index: u64) -> Vec<u8> {
+        match self {
+            Candidates::Words(words) => words[index as usize].clone(),
+            Candidates::Digits { min, max } => {
+                for digits in *min..=*max {
+                    let count = 10u64.pow(digits as u32);
+                    if index < count {
+                        return format!("{:01$}", index, digits).into_bytes();
+                    }
+                    index -= count;
+                }
+                unreachable!("index is below len()")
+            },
+        }
+    }
+}
+
+/**
+ * A key that decrypts the root's "." entry. `checksum` tells whether the
+ * header's checksum agrees, clients with a changed checksum plaintext don't.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMatch {
+    pub key: Vec<u8>,
+    pub checksum: bool,
+}
+
+/// Tries every candidate on `threads` threads (0 uses every core) and returns
+/// the first key that turns the archive's first entry into the root ".".
+/// Keys Blowfish can't take, empty or over 56 bytes, are skipped.
+pub fn find_key(pk2_path: &str, candidates: &Candidates, threads: usize) -> io::Result<Option<KeyMatch>> {
+    if let Candidates::Digits { min, max } = candidates {
+        if *min == 0 || *max > 19 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Numeric keys are 1 to 19 digits, not {} to {}.", min, max)));
+        }
+    }
+    let mut known = [0; (SKIP_HEADER_SIZE + ENTRY_SIZE) as usize];
+    File::open(pk2_path)?.read_exact(&mut known)?;
+    let header = Header::from_bytes(&known[..SKIP_HEADER_SIZE as usize]);
+    let first_entry = &known[SKIP_HEADER_SIZE as usize..];
+
+    let threads = thread_count(threads);
+    let total = candidates.len();
+    let next = AtomicU64::new(0);
+    let found = AtomicBool::new(false);
+    let result = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while !found.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= total {
+                        break;
+                    }
+                    let key = candidates.get(index);
+                    let blowfish = match BlowFish::new(&key) {
+                        Ok(blowfish) => blowfish,
+                        Err(_) => continue,
+                    };
+                    if is_root(&blowfish, first_entry) {
+                        found.store(true, Ordering::Relaxed);
+                        let checksum = header::checksum(&blowfish)[..3] == header.checksum[..3];
+                        result.lock().unwrap().get_or_insert(KeyMatch { key, checksum });
+                    }
+                }
+            });
+        }
+    });
+    Ok(result.into_inner().unwrap())
+}
+
+// The root's "." starts the first block and points back at it. Its type
+// and name rule out nearly every key, the whole entry settles it.
+fn is_root(blowfish: &BlowFish, encrypted: &[u8]) -> bool {
+    let mut entry = [0; ENTRY_SIZE as usize];
+    entry.copy_from_slice(encrypted);
+    blowfish.decrypt_in_place(&mut entry[..8]).expect("8 bytes are a block");
+    if entry[0] != DIRECTORY || &entry[1..3] != b".\0" {
+        return false;
+    }
+    blowfish.decrypt_in_place(&mut entry[8..]).expect("120 bytes are 15 blocks");
+    Entry::from_bytes(&entry).position == SKIP_HEADER_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{find_key, Candidates, KeyMatch};
+    use crate::builder::pack;
+
+    #[test]
+    fn test_find_key() {
+        let src = std::env::temp_dir().join(format!("pk2-keysearch-{}", std::process::id()));
+        let pk2 = format!("{}.pk2", src.to_str().unwrap());
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        pack(&src, &pk2, b"0417", None).unwrap();
+
+        let words = Candidates::from_wordlist(b"169841\r\n\nnot it\n0417\n");
+        let expected = KeyMatch { key: b"0417".to_vec(), checksum: true };
+        assert_eq!(find_key(&pk2, &words, 2).unwrap(), Some(expected.clone()));
+        assert_eq!(find_key(&pk2, &Candidates::Digits { min: 1, max: 4 }, 0).unwrap(), Some(expected));
+        assert_eq!(find_key(&pk2, &Candidates::Digits { min: 1, max: 3 }, 0).unwrap(), None);
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_file(pk2).unwrap();
+    }
+}
@@ -1,38 +1,169 @@
+use pyo3::exceptions::ValueError;
 use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use pyo3::wrap_pyfunction;
 
 use bytes::{Buf, BufMut};
-use std::iter::Iterator;
-use std::fs::OpenOptions;
-use std::io::{self, 
-    Read, BufReader, 
-    Write, BufWriter, 
-    Seek, SeekFrom, 
-};
-
-
-mod blowfish;
-use crate::blowfish::BlowFish;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+mod allocator;
+mod archive;
+pub mod blowfish;
+mod builder;
+mod check;
+mod client_version;
+mod compact;
+mod extract;
+mod header;
+mod history;
+mod journal;
+mod keysearch;
+mod lock;
+mod options;
+mod orphan;
+mod progress;
+mod rekey;
+mod repair;
+mod stats;
+mod transaction;
+mod writer;
+pub use crate::allocator::{FreeSpaceMap, Region};
+pub use crate::archive::{Archive, ArchiveWriter, Mode};
+pub use crate::blowfish::{BlowFish, Padding, Variant};
+pub use crate::builder::{pack, Pk2Builder};
+pub use crate::check::{CheckReport, Problem};
+pub use crate::client_version::{ClientVersion, SV_T_PATH};
+pub use crate::compact::{compact, compact_in_place, compact_with_monitor, compact_in_place_with_monitor, CompactReport};
+pub use crate::extract::{ExtractOptions, ExtractReport};
+pub use crate::header::Header;
+pub use crate::history::Version;
+pub use crate::keysearch::{find_key, Candidates, KeyMatch};
+pub use crate::options::OpenOptions;
+pub use crate::orphan::Orphan;
+pub use crate::progress::{CancellationToken, Monitor, Progress};
+pub use crate::repair::{repair, repair_with_options, RepairReport};
+pub use crate::stats::Stats;
+pub use crate::transaction::Transaction;
+use crate::transaction::Operation;
 
 #[pymodule]
 fn pk2(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Entry>().unwrap();
     m.add_class::<Extractor>().unwrap();
+    m.add_class::<PyTransaction>().unwrap();
+    m.add_class::<PyBlowFish>().unwrap();
+    m.add_wrapped(wrap_pyfunction!(py_compact)).unwrap();
+    m.add_wrapped(wrap_pyfunction!(py_compact_in_place)).unwrap();
+    m.add_wrapped(wrap_pyfunction!(py_pack)).unwrap();
+    m.add_wrapped(wrap_pyfunction!(py_repair)).unwrap();
     Ok(())
 }
 
+/// Returns how many bytes the compaction reclaimed.
+#[pyfunction]
+#[name = "compact"]
+fn py_compact(src: &str, dst: &str, sort: Option<bool>, progress: Option<PyObject>,
+              key: Option<&[u8]>) -> PyResult<u64> {
+    let mut options = OpenOptions::new();
+    options.key(key.unwrap_or(PK2_KEYS));
+    let report = with_py_monitor(progress, |monitor|
+        compact_with_monitor(src, dst, sort.unwrap_or(false), &options, &monitor))?;
+    Ok(report.reclaimed())
+}
+
+#[pyfunction]
+#[name = "compact_in_place"]
+fn py_compact_in_place(path: &str, sort: Option<bool>, progress: Option<PyObject>,
+                       key: Option<&[u8]>) -> PyResult<u64> {
+    let mut options = OpenOptions::new();
+    options.key(key.unwrap_or(PK2_KEYS));
+    let report = with_py_monitor(progress, |monitor|
+        compact_in_place_with_monitor(path, sort.unwrap_or(false), &options, &monitor))?;
+    Ok(report.reclaimed())
+}
+
+/// Packs a directory into a new archive, returns the archive's size.
+#[pyfunction]
+#[name = "pack"]
+fn py_pack(src: &str, dst: &str, key: Option<&[u8]>, timestamp: Option<u64>,
+           progress: Option<PyObject>) -> PyResult<u64> {
+    let mut builder = Pk2Builder::new(key.unwrap_or(PK2_KEYS))?;
+    if let Some(timestamp) = timestamp {
+        builder.timestamp(timestamp);
+    }
+    with_py_monitor(progress, |monitor| {
+        builder.monitor(monitor).add_directory(src)?;
+        builder.write(dst)
+    })
+}
+
+/// Runs `operation` with a monitor calling `progress(bytes, entries, path)` after every entry.
+/// It's cancelled when `progress` returns False or raises, an exception is raised again here.
+fn with_py_monitor<T>(progress: Option<PyObject>, operation: impl FnOnce(Monitor) -> io::Result<T>) -> PyResult<T> {
+    let raised: Arc<Mutex<Option<PyObject>>> = Arc::new(Mutex::new(None));
+    let mut monitor = Monitor::new();
+    if let Some(progress) = progress {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let raised = raised.clone();
+        monitor.cancel_with(token).on_progress(move |update| {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            match progress.call1(py, (update.bytes, update.entries, update.path)) {
+                Ok(result) if matches!(result.extract::<bool>(py), Ok(false)) => canceller.cancel(),
+                Ok(_) => {},
+                Err(err) => {
+                    // a PyErr can't cross threads, the exception object can.
+                    raised.lock().unwrap().get_or_insert_with(|| err.into_py(py));
+                    canceller.cancel();
+                },
+            }
+        });
+    }
+
+    let result = operation(monitor);
+    let raised = raised.lock().unwrap().take();
+    match raised {
+        Some(exception) => {
+            let gil = Python::acquire_gil();
+            Err(PyErr::from_instance(exception.as_ref(gil.python())))
+        },
+        None => Ok(result?),
+    }
+}
+
+/// Salvages `src` into a new archive, returns the lines of the repair log.
+#[pyfunction]
+#[name = "repair"]
+fn py_repair(src: &str, dst: &str, key: Option<&[u8]>) -> PyResult<Vec<String>> {
+    let mut options = OpenOptions::new();
+    options.key(key.unwrap_or(PK2_KEYS));
+    Ok(repair_with_options(src, dst, &options)?.log)
+}
+
 
 const ENTRY_SIZE: u64 = 128;
+const ENTRIES_PER_BLOCK: u64 = 20;
+const BLOCK_SIZE: u64 = ENTRY_SIZE * ENTRIES_PER_BLOCK;
 const SKIP_HEADER_SIZE: u64 = 256;
 const PK2_KEYS: &[u8] = &[0x32, 0xCE, 0xDD, 0x7C, 0xBC, 0xA8];
+const EMPTY: u8 = 0;
 const DIRECTORY: u8 = 1;
 const FILE: u8 = 2;
 
+// FILETIME counts 100ns intervals since 1601-01-01.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
 /**
  * Entries should be of Size 128 Byte.
  */
 #[pyclass]
 #[derive(Clone, Copy)]
-struct Entry {
+pub struct Entry {
 
     #[pyo3(get)]
     offset: u64,            // for use in code, not saved in data
@@ -67,11 +198,12 @@ struct Entry {
 #[pymethods]
 impl Entry {
     #[getter]
-    fn name(&self) -> String {
-        let name: Vec<u8> = self.name.iter().filter(|chr| chr > &&0).map(|x| *x).collect();
-        String::from_utf8(name).unwrap_or(String::from("Couldn't"))
+    pub fn name(&self) -> String {
+        let name: Vec<u8> = self.name.iter().filter(|chr| chr > &&0).copied().collect();
+        String::from_utf8(name).unwrap_or_else(|_| String::from("Couldn't"))
     }
 
+    #[allow(clippy::inherent_to_string, clippy::wrong_self_convention)]
     fn to_string(&self) -> String {
         format!("Entry<type: {}, name: {}, position: {}, size: {}, next_chain: {}>",
                     self.entry_type, self.name(), self.position, self.size, self.next_chain)
@@ -79,6 +211,93 @@ impl Entry {
 }
 
 impl Entry {
+    /// `DIRECTORY` (1) or `FILE` (2), `EMPTY` (0) for an unused slot.
+    pub fn entry_type(&self) -> u8 {
+        self.entry_type
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.entry_type == FILE
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.entry_type == DIRECTORY
+    }
+
+    /// Where the entry itself is stored in the archive.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// A file's data, or a directory's first entry block.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // The dates are FILETIMEs, 100ns intervals since 1601-01-01.
+    pub fn access_date(&self) -> u64 {
+        self.access_date
+    }
+
+    pub fn create_date(&self) -> u64 {
+        self.create_date
+    }
+
+    pub fn modify_date(&self) -> u64 {
+        self.modify_date
+    }
+
+    fn new(entry_type: u8, name: &str, position: u64, size: u32) -> io::Result<Self> {
+        let mut entry = Self::empty();
+        entry.set_name(name)?;
+        entry.entry_type = entry_type;
+        entry.access_date = filetime_now();
+        entry.create_date = entry.access_date;
+        entry.modify_date = entry.access_date;
+        entry.position = position;
+        entry.size = size;
+        Ok(entry)
+    }
+
+    fn empty() -> Self {
+        Self {
+            offset: 0,
+            entry_type: EMPTY,
+            name: [0; 81],
+            access_date: 0,
+            create_date: 0,
+            modify_date: 0,
+            position: 0,
+            size: 0,
+            next_chain: 0,
+            padding: 0
+        }
+    }
+
+    fn set_name(&mut self, name: &str) -> io::Result<()> {
+        // the name has to keep at least one NUL at the end.
+        if name.is_empty() || name.len() >= self.name.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Invalid entry name: {:?}.", name)));
+        }
+        self.name = [0; 81];
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    fn is_used(&self) -> bool {
+        self.entry_type == DIRECTORY || self.entry_type == FILE
+    }
+
+    // "." and ".." point back at the directory itself and at its parent.
+    fn is_navigation(&self) -> bool {
+        self.entry_type == DIRECTORY && (self.name() == "." || self.name() == "..")
+    }
+
     fn from_bytes(mut buffer: &[u8]) -> Self {
         let entry_type = buffer.get_u8();
         let mut name = [0; 81];
@@ -97,11 +316,11 @@ impl Entry {
         }
     }
 
-    fn into_bytes(&self) -> Vec<u8> {
+    fn into_bytes(self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::with_capacity(ENTRY_SIZE as usize);
         
         buffer.put_u8(self.entry_type);
-        buffer.write(&self.name).unwrap();
+        buffer.put_slice(&self.name);
         buffer.put_u64_le(self.access_date);
         buffer.put_u64_le(self.create_date);
         buffer.put_u64_le(self.modify_date);
@@ -115,172 +334,373 @@ impl Entry {
 
 #[pyclass]
 pub struct Extractor {
-    pk2_path: String,
-    blowfish: BlowFish,
-    root: Option<Entry>,
+    archive: Archive,
 }
 
 #[pymethods]
 impl Extractor {
+    /// `mode` is "r" for read-only, the default, or "r+" to allow changes.
+    /// `signature`, `version` and `checksum_plaintext` replace JoyMax's, see `OpenOptions`,
+    /// a lenient open turns what doesn't match into warnings.
     #[new]
-    pub fn new(pk2_path: Option<&str>) -> PyResult<Self> {
-        let mut extractor = Self {
-            pk2_path: pk2_path.unwrap().to_string(),
-            blowfish: BlowFish::new(PK2_KEYS, 0, 6),
-            root: None
-        };
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(pk2_path: Option<&str>, key: Option<&[u8]>, mode: Option<&str>,
+               signature: Option<&[u8]>, version: Option<u32>, checksum_plaintext: Option<&[u8]>,
+               lenient: Option<bool>) -> PyResult<Self> {
+        let mut options = OpenOptions::new();
+        options.key(key.unwrap_or(PK2_KEYS)).lenient(lenient.unwrap_or(false));
+        options.mode(match mode.unwrap_or("r") {
+            "r" => Mode::ReadOnly,
+            "r+" => Mode::ReadWrite,
+            mode => return Err(ValueError::py_err(format!("Invalid mode: {:?}, expected \"r\" or \"r+\".", mode))),
+        });
+        if let Some(signature) = signature {
+            options.signature(signature);
+        }
+        if let Some(version) = version {
+            options.version(version);
+        }
+        if let Some(plaintext) = checksum_plaintext {
+            options.checksum_plaintext(plaintext);
+        }
 
-        extractor.root = extractor.get_entry_at_offset(SKIP_HEADER_SIZE); 
-        Ok(extractor)
+        let archive = options.open(pk2_path.unwrap())?;
+        if !archive.header_warnings().is_empty() {
+            let gil = Python::acquire_gil();
+            let warnings = gil.python().import("warnings")?;
+            for warning in archive.header_warnings() {
+                warnings.call1("warn", (warning.as_str(),))?;
+            }
+        }
+        Ok(Self { archive })
+    }
+
+    fn header_warnings(&self) -> Vec<String> {
+        self.archive.header_warnings().to_vec()
     }
 
-    fn list(&self, directory: Option<&str>) -> Vec<Entry>
-    {
+    fn list(&self, directory: Option<&str>) -> PyResult<Vec<Entry>> {
         let directory = directory.expect("Invalid Directory.");
-        let path_node = if directory.eq_ignore_ascii_case(".") { 
-            self.root 
-        } else { 
-            self.get_entry_of_path(directory)
-        };
+        Ok(self.archive.list(directory)?)
+    }
 
-        self.get_children_of_node(&path_node.unwrap())
+    /// Holes of the archive as (offset, size) pairs, see `Archive::free_space_map`.
+    fn free_space(&self) -> PyResult<Vec<(u64, u64)>> {
+        let map = self.archive.free_space_map()?;
+        Ok(map.regions().iter().map(|region| (region.offset, region.size)).collect())
     }
 
     fn extract(&self, path: Option<&str>) -> PyResult<(Entry, Vec<u8>)> {
         let path = path.expect("Invalid Path.");
-        let entry = self.get_entry_of_path(path).unwrap();
-        let bytes = self.read_bytes(entry.position, entry.size).unwrap();
-        Ok((entry, bytes))
-    }
-
-    fn patch(&self, path: &str, buffer: &[u8]) -> PyResult<()> {
-        // Get the entry, if doesn't exist will panic!
-        let mut entry = self.get_entry_of_path(path).unwrap();
-
-        // we have the entry now so we will write the buffer
-        // first to get the offset where it got written
-        // we appended buffer at the end of the file
-        // and ignored the actual old file, it still exists but we cant get it
-        let offset = self.append_bytes(buffer).unwrap();
-
-        // now we will update our existing entry 
-        // with the new size and position(which is it's new location)
-        entry.position = offset;
-        entry.size = buffer.len() as u32;
-        let encrypted = self.blowfish.encrypt(&entry.into_bytes(), 128);
-        self.write_bytes(entry.offset, &encrypted).expect(
-            "Couldn't write updated entry.");
+        Ok(self.archive.extract(path)?)
+    }
+
+    /// Extracts `path` into `destination` on `threads` threads (every core by default),
+    /// keeping the files matching `include` but not `exclude`. Returns how many were written.
+    /// `progress` is called as for `compact`, from the extracting threads.
+    #[allow(clippy::too_many_arguments)]
+    fn extract_all(&self, py: Python, path: &str, destination: &str, threads: Option<usize>,
+                   include: Option<Vec<String>>, exclude: Option<Vec<String>>,
+                   progress: Option<PyObject>) -> PyResult<usize> {
+        let mut options = ExtractOptions::new();
+        options.threads(threads.unwrap_or(0));
+        for pattern in include.unwrap_or_default() {
+            options.include(&pattern);
+        }
+        for pattern in exclude.unwrap_or_default() {
+            options.exclude(&pattern);
+        }
+        let report = with_py_monitor(progress, |monitor| {
+            options.monitor(monitor);
+            // the threads need the GIL to call `progress`.
+            py.allow_threads(|| self.archive.extract_all(path, destination, &options))
+        })?;
+        Ok(report.files)
+    }
+
+    fn patch(&self, py: Python, path: &str, buffer: &[u8]) -> PyResult<()> {
+        self.change(py, |writer| writer.patch(path, buffer))
+    }
+
+    fn add(&self, py: Python, path: &str, buffer: &[u8]) -> PyResult<()> {
+        self.change(py, |writer| writer.add(path, buffer))
+    }
+
+    fn delete(&self, py: Python, path: &str) -> PyResult<()> {
+        self.change(py, |writer| writer.delete(path))
+    }
+
+    fn rename(&self, py: Python, from: &str, to: &str) -> PyResult<()> {
+        self.change(py, |writer| writer.rename(from, to))
+    }
+
+    /// Every problem `Archive::check` finds, described, empty if there are none.
+    fn check(&self, progress: Option<PyObject>) -> PyResult<Vec<String>> {
+        let report = with_py_monitor(progress, |monitor| self.archive.check_with_monitor(&monitor))?;
+        Ok(report.problems.iter().map(|problem| problem.to_string()).collect())
+    }
+
+    /// `Archive::stats` as a dict with the same keys as the struct's fields.
+    fn stats(&self, py: Python) -> PyResult<PyObject> {
+        let stats = self.archive.stats()?;
+        let dict = PyDict::new(py);
+        dict.set_item("file_size", stats.file_size)?;
+        dict.set_item("files", stats.files)?;
+        dict.set_item("directories", stats.directories)?;
+        dict.set_item("payload_bytes", stats.payload_bytes)?;
+        dict.set_item("block_bytes", stats.block_bytes)?;
+        dict.set_item("orphaned_bytes", stats.orphaned_bytes)?;
+        dict.set_item("free_slots", stats.free_slots)?;
+        dict.set_item("fragmentation", stats.fragmentation)?;
+        dict.set_item("by_directory", stats.by_directory)?;
+        dict.set_item("by_extension", stats.by_extension)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Orphaned regions as (offset, size, owner) tuples, see `Archive::orphans`.
+    fn orphans(&self) -> PyResult<Vec<(u64, u64, Option<String>)>> {
+        let orphans = self.archive.orphans()?;
+        Ok(orphans.into_iter().map(|orphan| (orphan.region.offset, orphan.region.size, orphan.owner)).collect())
+    }
+
+    fn export_orphans(&self, directory: &str) -> PyResult<Vec<String>> {
+        let paths = self.archive.export_orphans(directory)?;
+        Ok(paths.into_iter().map(|path| path.to_string_lossy().into_owned()).collect())
+    }
+
+    fn enable_history(&self, py: Python) -> PyResult<()> {
+        let archive = &self.archive;
+        Ok(py.allow_threads(|| archive.enable_history())?)
+    }
+
+    /// Old versions of a file as (position, size, replaced) tuples, oldest first.
+    fn history(&self, path: &str) -> PyResult<Vec<(u64, u32, u64)>> {
+        let versions = self.archive.history(path)?;
+        Ok(versions.into_iter().map(|version| (version.position, version.size, version.replaced)).collect())
+    }
+
+    fn revert(&self, py: Python, path: &str, version: usize) -> PyResult<()> {
+        self.change(py, |writer| writer.revert(path, version))
+    }
+
+    fn client_version(&self) -> PyResult<u32> {
+        Ok(self.archive.client_version()?.0)
+    }
+
+    fn set_client_version(&self, py: Python, version: u32) -> PyResult<()> {
+        self.change(py, |writer| writer.set_client_version(ClientVersion(version)))
+    }
+
+    /// Raises the version in SV.T by one, returns the new one.
+    fn bump_client_version(&self, py: Python) -> PyResult<u32> {
+        Ok(self.change(py, |writer| writer.bump_client_version())?.0)
+    }
+
+    /// Re-encrypts the archive's entries with `new_key` and keeps using it from then on.
+    fn rekey(&mut self, new_key: &[u8]) -> PyResult<()> {
+        rekey::rekey(&self.archive, &BlowFish::new(new_key)?)?;
+        let mut options = self.archive.options.clone();
+        self.archive = options.key(new_key).open(&self.archive.pk2_path)?;
         Ok(())
     }
 
+    /// Collects changes to write all at once, see `ArchiveWriter::transaction`.
+    fn transaction(slf: PyRef<Self>) -> PyTransaction {
+        PyTransaction { extractor: slf.into(), operations: Vec::new() }
+    }
 }
 
-
 impl Extractor {
-    fn get_entry_of_path(&self, path: &str) -> Option<Entry> {
-        let path_parts = self.split_path(path);
+    /// Runs `change` on the archive's writer. Waiting for another writer
+    /// happens without the GIL, so other Python threads keep running.
+    fn change<T: Send>(&self, py: Python,
+                       change: impl FnOnce(&mut ArchiveWriter) -> io::Result<T> + Send) -> PyResult<T> {
+        let archive = &self.archive;
+        Ok(py.allow_threads(|| change(&mut archive.writer()?))?)
+    }
+}
 
-        let mut graph_path: Vec<Entry> = Vec::new();
-        graph_path.push(self.root.unwrap());
-        for part in path_parts.iter() {
-            graph_path.push(self.get_entry_of_part(part, &graph_path.last().unwrap()).unwrap());
-        }
+#[pyclass(name = Transaction)]
+pub struct PyTransaction {
+    extractor: Py<Extractor>,
+    operations: Vec<Operation>,
+}
 
-        graph_path.last().map(|entry| *entry)
+#[pymethods]
+impl PyTransaction {
+    fn add(&mut self, path: &str, buffer: &[u8]) {
+        self.operations.push(Operation::Add(path.to_string(), buffer.to_vec()));
     }
 
-    fn get_entry_of_part(&self, path: &str, cursor: &Entry) -> Option<Entry> {
-        if cursor.entry_type == FILE {
-            panic!("Files can't have children, hence can't be searched in.");
-        }
-
-        let children = self.get_children_of_node(&cursor);
-        for child in children.into_iter() {
-            if child.name()[..].eq_ignore_ascii_case(path) {
-                return Some(child);
-            }
-        }
-        panic!(format!("Can't find specified path: {}.", path));
+    fn replace(&mut self, path: &str, buffer: &[u8]) {
+        self.operations.push(Operation::Replace(path.to_string(), buffer.to_vec()));
     }
 
-    fn get_children_of_node(&self, entry: &Entry) -> Vec<Entry> {
-        if entry.entry_type != DIRECTORY {
-            return vec![];
-        }
-        let mut children: Vec<Entry> = Vec::new();
-        let mut current_index = entry.position + 128;
+    fn delete(&mut self, path: &str) {
+        self.operations.push(Operation::Delete(path.to_string()));
+    }
 
-        loop {
-            let walking_node = self.get_entry_at_offset(current_index).unwrap();
+    fn rename(&mut self, from: &str, to: &str) {
+        self.operations.push(Operation::Rename(from.to_string(), to.to_string()));
+    }
 
-            if walking_node.entry_type > 2 || walking_node.entry_type <= 0 {
-                break;
-            }
+    fn revert(&mut self, path: &str, version: usize) {
+        self.operations.push(Operation::Revert(path.to_string(), version));
+    }
 
-            children.push(walking_node);
+    fn commit(&mut self, py: Python) -> PyResult<()> {
+        let extractor = self.extractor.borrow(py);
+        let operations = std::mem::take(&mut self.operations);
+        extractor.change(py, |writer| writer.apply(operations))
+    }
 
-            if walking_node.next_chain > 0 && walking_node.next_chain != current_index {
-                current_index = walking_node.next_chain;
-            } else {
-                current_index += ENTRY_SIZE;
-            }
+    fn rollback(&mut self) {
+        self.operations.clear();
+    }
+}
 
-            // If at the end of the chain
-            if walking_node.offset + 128 == walking_node.position {
-                break;
-            }
-        }
+/// Blowfish for the rest of the client's data, bytes in and bytes out.
+#[pyclass(name = BlowFish)]
+pub struct PyBlowFish {
+    blowfish: BlowFish,
+}
 
-        children
+#[pymethods]
+impl PyBlowFish {
+    /// Uses `length` bytes of `key` from `offset` on, the whole key by default.
+    /// `variant` is "joymax", the default, or "standard".
+    #[new]
+    fn new(key: &[u8], offset: Option<usize>, length: Option<usize>, variant: Option<&str>) -> PyResult<Self> {
+        let offset = offset.unwrap_or(0);
+        let length = length.unwrap_or_else(|| key.len().saturating_sub(offset));
+        let mut blowfish = BlowFish::with_key_range(key, offset, length)?;
+        blowfish.variant(match variant.unwrap_or("joymax") {
+            "joymax" => Variant::JoyMax,
+            "standard" => Variant::Standard,
+            variant => return Err(ValueError::py_err(format!("Invalid variant: {:?}, expected \"joymax\" or \"standard\".", variant))),
+        });
+        Ok(Self { blowfish })
     }
 
-    fn get_entry_at_offset(&self, offset: u64) -> Option<Entry> {
-        let bytes = self.read_bytes(offset, ENTRY_SIZE as u32);
-        let decrypted = self.blowfish.decrypt(&bytes.unwrap(), ENTRY_SIZE as u32);
-        let mut entry = Entry::from_bytes(&decrypted);
-        entry.offset = offset;
-        Some(entry)
+    /// `padding` is "none", the default, "zero" or "pkcs7".
+    fn encrypt(&self, py: Python, data: &[u8], padding: Option<&str>) -> PyResult<PyObject> {
+        let encrypted = self.blowfish.encrypt_padded(data, py_padding(padding)?)?;
+        Ok(PyBytes::new(py, &encrypted).into())
     }
 
-    fn read_bytes(&self, offset: u64, count: u32) -> io::Result<Vec<u8>> {
-        let mut buffer = vec![0u8; count as usize];
-        let mut reader = BufReader::new(OpenOptions::new().read(true).open(&self.pk2_path)?);
-        reader.seek(SeekFrom::Start(offset.into()))?;
-        reader.read_exact(&mut buffer)?;
-        Ok(buffer)
+    fn decrypt(&self, py: Python, data: &[u8], padding: Option<&str>) -> PyResult<PyObject> {
+        let decrypted = self.blowfish.decrypt_padded(data, py_padding(padding)?)?;
+        Ok(PyBytes::new(py, &decrypted).into())
     }
+}
 
-    fn append_bytes(&self, buffer: &[u8]) -> io::Result<u64> {
-        let mut writer = BufWriter::new(OpenOptions::new().append(true).open(&self.pk2_path)?);
-        let index = writer.seek(SeekFrom::End(0)).unwrap();
-        writer.write_all(buffer)?;
-        Ok(index)
+fn py_padding(padding: Option<&str>) -> PyResult<Padding> {
+    match padding.unwrap_or("none") {
+        "none" => Ok(Padding::None),
+        "zero" => Ok(Padding::Zero),
+        "pkcs7" => Ok(Padding::Pkcs7),
+        padding => Err(ValueError::py_err(format!("Invalid padding: {:?}, expected \"none\", \"zero\" or \"pkcs7\".", padding))),
     }
+}
 
-    fn write_bytes(&self, offset: u64, buffer: &[u8]) -> io::Result<()> {
-        let mut writer = BufWriter::new(OpenOptions::new().write(true).open(&self.pk2_path)?);
-        writer.seek(SeekFrom::Start(offset.into()))?;
-        writer.write_all(buffer)?;
-        Ok(())
+// "." on its own is the root, so it's dropped like empty parts.
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').collect::<Vec<&str>>()
+                    .into_iter()
+                    .filter(|part| !part.is_empty() && *part != ".")
+                    .collect()
+}
+
+/// `split_path` for a path that gets written, every part has to be a plain name.
+fn split_new_path(path: &str) -> io::Result<Vec<&str>> {
+    let parts = split_path(path);
+    match parts.iter().find(|part| !is_plain_name(part)) {
+        Some(part) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{:?} can't be part of a path in the archive.", part))),
+        None => Ok(parts),
     }
+}
+
+/// `name` inside `directory`, "" being the root.
+fn join_path(directory: &str, name: &str) -> String {
+    if directory.is_empty() { name.to_string() } else { format!("{}/{}", directory, name) }
+}
+
+/// Whether `name` stays inside its directory when joined to a filesystem path:
+/// no separators, not "." or "..", nothing a platform reads as absolute.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\'])
+        && matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
 
-    fn split_path<'a>(&self, path: &'a str) -> Vec<&'a str> {
-        path.split('/').collect::<Vec<&str>>()
-                        .into_iter()
-                        .filter(|part| part.len() > 0)
-                        .collect()
+/// Syncs the directory holding `path`, so a file created in it
+/// or renamed into it is still there after a crash.
+#[cfg(unix)]
+fn sync_directory(path: &str) -> io::Result<()> {
+    let directory = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(directory)?.sync_all()
+}
+
+// Windows can't open a directory as a file, NTFS journals its metadata anyway.
+#[cfg(not(unix))]
+fn sync_directory(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// 0 threads means one for every core.
+fn thread_count(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
     }
 }
 
+fn filetime_now() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    FILETIME_UNIX_EPOCH + since_epoch.as_nanos() as u64 / 100
+}
+
 #[cfg(test)]
 mod tests {
-    use std::convert::TryInto;
-    use super::{Entry, Extractor};
+    use std::fs;
+    use crate::archive::Archive;
+    use crate::blowfish::BlowFish;
+    use super::{Entry, Extractor, Header, PK2_KEYS, DIRECTORY, FILE, ENTRIES_PER_BLOCK, SKIP_HEADER_SIZE, FILETIME_UNIX_EPOCH};
+
+    // An archive holding nothing but the root directory.
+    pub(crate) fn create_empty_archive(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pk2-{}-{}.pk2", name, std::process::id()));
+        let blowfish = BlowFish::new(PK2_KEYS).unwrap();
+
+        let mut bytes = Header::new(&blowfish).into_bytes();
+        let mut root = vec![Entry::empty(); ENTRIES_PER_BLOCK as usize];
+        root[0] = Entry::new(DIRECTORY, ".", SKIP_HEADER_SIZE, 0).unwrap();
+        let mut block: Vec<u8> = root.into_iter().flat_map(Entry::into_bytes).collect();
+        blowfish.encrypt_in_place(&mut block).unwrap();
+        bytes.extend_from_slice(&block);
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    // `entry` as it's stored, to damage an archive with.
+    pub(crate) fn encrypt_entry(archive: &Archive, entry: Entry) -> Vec<u8> {
+        let mut bytes = entry.into_bytes();
+        archive.blowfish.encrypt_in_place(&mut bytes).unwrap();
+        bytes
+    }
+
+    pub(crate) fn write_entry(archive: &Archive, entry: Entry) {
+        archive.write_all_bytes(&[(entry.offset, encrypt_entry(archive, entry))]).unwrap();
+    }
     
     #[test]
     fn test_entry_conversion() {
         let buffer: Vec<u8> = (0..128).map(|i| i as u8 ).collect();
-        let entry = Entry::from_bytes(buffer.as_slice().try_into().unwrap());
+        let entry = Entry::from_bytes(buffer.as_slice());
         let back = entry.into_bytes();
 
         assert_eq!(back.len(), 128);
@@ -289,10 +709,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_entry_accessors() {
+        let entry = Entry::new(FILE, "item.txt", 4096, 12).unwrap();
+        assert_eq!(entry.name(), "item.txt");
+        assert!(entry.is_file() && !entry.is_directory());
+        assert_eq!((entry.entry_type(), entry.position(), entry.size()), (FILE, 4096, 12));
+        assert!(entry.modify_date() > FILETIME_UNIX_EPOCH);
+        assert_eq!(entry.create_date(), entry.access_date());
+    }
+
     #[test]
     fn test_extract() {
         let path = "/home/sorcerer/Desktop/Media.pk2";
-        let extractor = Extractor::new(Some(path));
+        let extractor = Extractor::new(Some(path), None, None, None, None, None, None);
         let _output = extractor.unwrap().extract(
             Some("server_dep/silkroad/textdata/siegefortressreward.txt"));
     }
@@ -300,7 +730,7 @@ mod tests {
     #[test]
     fn test_list() {
         let path = "/home/sorcerer/Desktop/Media.pk2";
-        let extractor = Extractor::new(Some(path));
+        let extractor = Extractor::new(Some(path), None, None, None, None, None, None);
         let _output = extractor.unwrap().list(
             Some("server_dep/silkroad/"));
     }
@@ -308,13 +738,11 @@ mod tests {
     #[test]
     fn test_patch() {
         let path = "/home/sorcerer/Desktop/Media.pk2";
-        let extractor = Extractor::new(Some(path));
-        let _index = extractor.unwrap().patch(
+        let extractor = Extractor::new(Some(path), None, Some("r+"), None, None, None, None);
+        let gil = pyo3::Python::acquire_gil();
+        let _index = extractor.unwrap().patch(gil.python(),
             "server_dep/silkroad/textdata/siegefortressreward.txt", 
             &[1,2,3,4,5,6,8,9]
         );
     }
-
 }
-
-
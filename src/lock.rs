@@ -0,0 +1,31 @@
+use std::fs::{File, TryLockError};
+use std::io;
+
+/**
+ * An exclusive advisory lock (flock on unix) on an archive, held until this
+ * is dropped. It only keeps out other handles that lock too, the game client
+ * doesn't, so it can still write while we hold it.
+ */
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Waits for whoever holds it to let go.
+    pub(crate) fn exclusive(pk2_path: &str) -> io::Result<Self> {
+        let file = File::open(pk2_path)?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+
+    /// Fails with `WouldBlock` instead of waiting when someone else holds it.
+    pub(crate) fn try_exclusive(pk2_path: &str) -> io::Result<Self> {
+        let file = File::open(pk2_path)?;
+        file.try_lock().map_err(|err| match err {
+            TryLockError::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock,
+                format!("{} is being written by someone else.", pk2_path)),
+            TryLockError::Error(err) => err,
+        })?;
+        Ok(Self { _file: file })
+    }
+}
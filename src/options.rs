@@ -0,0 +1,177 @@
+use std::io;
+
+use crate::archive::{Archive, Mode};
+use crate::blowfish::BlowFish;
+use crate::header::{self, Header, CHECKSUM_PLAINTEXT, SIGNATURE, VERSION};
+use crate::PK2_KEYS;
+
+/**
+ * How to open an archive, `Archive::open` and friends use the defaults:
+ * the stock key, read-only, and JoyMax's signature, version and checksum
+ * plaintext, a header that differs in any of them fails the open.
+ * Modified clients change those, either set theirs or open leniently, which
+ * keeps going and lists what didn't match in `Archive::header_warnings`.
+ */
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    pub(crate) key: Vec<u8>,
+    pub(crate) mode: Mode,
+    signature: Vec<u8>,
+    version: u32,
+    pub(crate) checksum_plaintext: Vec<u8>,
+    lenient: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            key: PK2_KEYS.to_vec(),
+            mode: Mode::ReadOnly,
+            signature: SIGNATURE.to_vec(),
+            version: VERSION,
+            checksum_plaintext: CHECKSUM_PLAINTEXT.to_vec(),
+            lenient: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(&mut self, key: &[u8]) -> &mut Self {
+        self.key = key.to_vec();
+        self
+    }
+
+    pub fn mode(&mut self, mode: Mode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Up to 30 bytes, the rest of the header's field has to be NUL.
+    pub fn signature(&mut self, signature: &[u8]) -> &mut Self {
+        self.signature = signature.to_vec();
+        self
+    }
+
+    pub fn version(&mut self, version: u32) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    /// Encrypted with the key, the first 3 bytes are the header's checksum.
+    /// Has to be a multiple of 8 bytes long.
+    pub fn checksum_plaintext(&mut self, plaintext: &[u8]) -> &mut Self {
+        self.checksum_plaintext = plaintext.to_vec();
+        self
+    }
+
+    pub fn lenient(&mut self, lenient: bool) -> &mut Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn open(&self, pk2_path: &str) -> io::Result<Archive> {
+        Archive::open_with_options(pk2_path, self)
+    }
+
+    /// The header these options expect of an archive encrypted with `blowfish`.
+    pub(crate) fn header(&self, blowfish: &BlowFish) -> io::Result<Header> {
+        let mut header = Header::new(blowfish);
+        header.signature = self.padded_signature()?;
+        header.version = self.version;
+        header.checksum = header::checksum_with(blowfish, &self.checksum_plaintext)?;
+        Ok(header)
+    }
+
+    /// What about `header` doesn't match, an error unless lenient.
+    pub(crate) fn check_header(&self, header: &Header, blowfish: &BlowFish) -> io::Result<Vec<String>> {
+        let signature = self.padded_signature()?;
+        let mut mismatches = Vec::new();
+        if header.signature != signature {
+            mismatches.push(format!("Signature is {:?}, expected {:?}.",
+                String::from_utf8_lossy(trim_nul(&header.signature)), String::from_utf8_lossy(&self.signature)));
+        }
+        if header.version != self.version {
+            mismatches.push(format!("Version is {:#010x}, expected {:#010x}.", header.version, self.version));
+        }
+        if header.checksum[..3] != header::checksum_with(blowfish, &self.checksum_plaintext)?[..3] {
+            mismatches.push("Checksum doesn't match, wrong key or checksum plaintext.".to_string());
+        }
+
+        if !mismatches.is_empty() && !self.lenient {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, mismatches.join(" ")));
+        }
+        Ok(mismatches)
+    }
+
+    fn padded_signature(&self) -> io::Result<[u8; 30]> {
+        let mut signature = [0; 30];
+        if self.signature.len() > signature.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("A signature is up to {} bytes, not {}.", signature.len(), self.signature.len())));
+        }
+        signature[..self.signature.len()].copy_from_slice(&self.signature);
+        Ok(signature)
+    }
+}
+
+fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    &bytes[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use super::OpenOptions;
+    use crate::archive::{Archive, Mode};
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_custom_header() {
+        let path = create_empty_archive("options");
+        let archive = Archive::open_rw(&path).unwrap();
+        assert!(archive.header_warnings().is_empty());
+        let mut header = archive.header().unwrap();
+        drop(archive);
+        header.signature = [0; 30];
+        header.signature[..12].copy_from_slice(b"Private Pak!");
+        header.version = 7;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[..256].copy_from_slice(&header.into_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let err = Archive::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(OpenOptions::new().key(b"169841").lenient(true).open(&path).is_ok());
+
+        let archive = OpenOptions::new().lenient(true).open(&path).unwrap();
+        assert_eq!(archive.header_warnings(), &[
+            "Signature is \"Private Pak!\", expected \"JoyMax File Manager!\\n\".".to_string(),
+            "Version is 0x00000007, expected 0x01000002.".to_string(),
+        ]);
+        assert!(archive.writer().is_err());
+
+        let mut options = OpenOptions::new();
+        options.mode(Mode::ReadWrite).signature(b"Private Pak!").version(7);
+        let archive = options.open(&path).unwrap();
+        assert!(archive.header_warnings().is_empty());
+        drop(archive);
+
+        // rekeying keeps the header as it is, but for a checksum made from the options' plaintext.
+        options.checksum_plaintext(b"Private Checksum").lenient(true);
+        let archive = options.open(&path).unwrap().rekey(b"169841").unwrap();
+        assert_eq!(archive.header().unwrap().version, 7);
+        assert!(archive.header_warnings().is_empty());
+        drop(archive);
+        options.key(b"169841").lenient(false);
+        assert!(options.open(&path).is_ok());
+        assert!(options.checksum_plaintext(b"Joymax Pak File\0").open(&path).is_err());
+        assert!(options.checksum_plaintext(b"odd").open(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::allocator::Region;
+use crate::archive::Archive;
+use crate::{is_plain_name, join_path, Entry, DIRECTORY, FILE};
+
+/**
+ * Bytes of the archive no entry points at anymore, most of the time an old
+ * version of a patched file. `owner` is the path of the file it most likely
+ * belonged to, if there is a good guess.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Orphan {
+    pub region: Region,
+    pub owner: Option<String>,
+}
+
+/// Every free region, see `Archive::free_space_map`, with a guess at its owner.
+///
+/// Files are usually written in the order of their entries, so a file's data
+/// follows the data of the entry before it. A patched file moves away and leaves
+/// a hole between the data of its neighbours, the entry right after the one
+/// whose data ends where the hole starts (or right before the one whose data
+/// starts where it ends) is taken as the owner.
+pub(crate) fn orphans(archive: &Archive) -> io::Result<Vec<Orphan>> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    collect_files(archive, &archive.root, "", &mut visited, &mut files)?;
+
+    let mut ending_at = HashMap::new();
+    let mut starting_at = HashMap::new();
+    for (i, (_, entry)) in files.iter().enumerate() {
+        if entry.size > 0 {
+            ending_at.insert(entry.position + entry.size as u64, i);
+            starting_at.insert(entry.position, i);
+        }
+    }
+
+    Ok(archive.scan_free_space()?.regions().iter().map(|region| {
+        let after = ending_at.get(&region.offset).map(|i| i + 1);
+        let before = starting_at.get(&region.end()).and_then(|i| i.checked_sub(1));
+        let owner = after.into_iter().chain(before)
+                         .find(|i| *i < files.len())
+                         .map(|i| files[i].0.clone());
+        Orphan { region: *region, owner }
+    }).collect())
+}
+
+/// Writes every orphan to its own file in `directory`, named after its offset
+/// and the name of its owner, and returns the paths in the same order.
+/// Orphans are streamed, however large they are.
+pub(crate) fn export(archive: &Archive, directory: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(directory)?;
+    let mut source = File::open(&archive.pk2_path)?;
+    orphans(archive)?.into_iter().map(|orphan| {
+        // the owner's name comes from the archive, one that isn't plain could leave `directory`.
+        let name = match orphan.owner.as_ref().and_then(|owner| owner.rsplit('/').next()) {
+            Some(owner) if is_plain_name(owner) => format!("{}-{}", orphan.region.offset, owner),
+            _ => format!("{}.bin", orphan.region.offset),
+        };
+        let path = directory.join(name);
+
+        source.seek(SeekFrom::Start(orphan.region.offset))?;
+        let mut out = BufWriter::new(File::create(&path)?);
+        let copied = io::copy(&mut (&mut source).take(orphan.region.size), &mut out)?;
+        if copied != orphan.region.size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("The orphan at {} is cut short by the end of the archive.", orphan.region.offset)));
+        }
+        out.flush()?;
+        Ok(path)
+    }).collect()
+}
+
+// Files in the order of their entries, directories followed right away.
+fn collect_files(archive: &Archive, directory: &Entry, path: &str,
+                 visited: &mut HashSet<u64>, files: &mut Vec<(String, Entry)>) -> io::Result<()> {
+    if !visited.insert(directory.position) {
+        return Ok(());
+    }
+    for child in archive.read_children(directory)? {
+        let child_path = join_path(path, &child.name());
+        if child.entry_type == DIRECTORY {
+            collect_files(archive, &child, &child_path, visited, files)?;
+        } else if child.entry_type == FILE {
+            files.push((child_path, child));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::archive::Archive;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_orphans() {
+        let path = create_empty_archive("orphans");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("textdata/a.txt", b"first a").unwrap();
+        archive.writer().unwrap().add("textdata/b.txt", b"first b").unwrap();
+        archive.writer().unwrap().add("textdata/c.txt", b"first c").unwrap();
+        let old = archive.extract("textdata/b.txt").unwrap().0;
+        archive.writer().unwrap().patch("textdata/b.txt", b"second b").unwrap();
+
+        let orphans = archive.orphans().unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].region.offset, old.position);
+        assert_eq!(orphans[0].owner.as_deref(), Some("textdata/b.txt"));
+
+        let directory = std::env::temp_dir().join(format!("pk2-orphans-{}", std::process::id()));
+        let exported = archive.export_orphans(&directory).unwrap();
+        assert_eq!(exported[0].file_name().unwrap().to_str().unwrap(), format!("{}-b.txt", old.position));
+        assert_eq!(fs::read(&exported[0]).unwrap(), b"first b");
+
+        fs::remove_dir_all(directory).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
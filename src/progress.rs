@@ -0,0 +1,160 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/**
+ * How far a long running operation got, `path` is the entry it just finished.
+ * `bytes` and `entries` count everything the `Monitor` was told about,
+ * so every operation should get a new one.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Progress<'a> {
+    pub bytes: u64,
+    pub entries: usize,
+    pub path: &'a str,
+}
+
+/**
+ * Stops the operations it was given to from another thread (or a progress
+ * callback). They fail with `Interrupted` at the next entry.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+type Callback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/**
+ * The progress callback and cancellation token of one operation, both optional.
+ * Extraction runs on several threads, so the callback can be called from any of them.
+ */
+#[derive(Clone, Default)]
+pub struct Monitor {
+    callback: Option<Callback>,
+    token: Option<CancellationToken>,
+    bytes: Arc<AtomicU64>,
+    entries: Arc<AtomicUsize>,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_progress<F: Fn(&Progress) + Send + Sync + 'static>(&mut self, callback: F) -> &mut Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn cancel_with(&mut self, token: CancellationToken) -> &mut Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Fails if the operation was cancelled, to be called before each entry.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match &self.token {
+            Some(token) if token.is_cancelled() => Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled.")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts one more entry of `bytes` as done.
+    pub(crate) fn advance(&self, bytes: u64, path: &str) -> io::Result<()> {
+        let progress = Progress {
+            bytes: self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes,
+            entries: self.entries.fetch_add(1, Ordering::Relaxed) + 1,
+            path,
+        };
+        if let Some(callback) = &self.callback {
+            callback(&progress);
+        }
+        self.check()
+    }
+}
+
+impl fmt::Debug for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Monitor")
+         .field("callback", &self.callback.is_some())
+         .field("token", &self.token)
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use super::{CancellationToken, Monitor};
+    use crate::archive::Archive;
+    use crate::compact::compact_with_monitor;
+    use crate::options::OpenOptions;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_progress_and_cancel() {
+        let path = create_empty_archive("progress");
+        let archive = Archive::open_rw(&path).unwrap();
+        for i in 0..5 {
+            archive.writer().unwrap().add(&format!("dir/{}.txt", i), &[0; 10]).unwrap();
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut monitor = Monitor::new();
+        let recorder = seen.clone();
+        monitor.on_progress(move |progress| {
+            recorder.lock().unwrap().push((progress.bytes, progress.entries, progress.path.to_string()));
+        });
+        archive.check_with_monitor(&monitor).unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen[4], (50, 5, "dir/4.txt".to_string()));
+
+        let token = CancellationToken::new();
+        let mut monitor = Monitor::new();
+        let canceller = token.clone();
+        monitor.cancel_with(token).on_progress(move |progress| {
+            if progress.entries == 2 {
+                canceller.cancel();
+            }
+        });
+        let err = archive.check_with_monitor(&monitor).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_compaction_leaves_nothing() {
+        let path = create_empty_archive("progress-compact");
+        let dst = path.replace("progress-compact", "progress-compact-dst");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        archive.writer().unwrap().add("b.txt", b"b").unwrap();
+
+        let token = CancellationToken::new();
+        let mut monitor = Monitor::new();
+        monitor.cancel_with(token.clone());
+        token.cancel();
+        assert!(compact_with_monitor(&path, &dst, false, &OpenOptions::new(), &monitor).is_err());
+        assert!(fs::metadata(&dst).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
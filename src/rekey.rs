@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use std::io;
+
+use crate::archive::Archive;
+use crate::blowfish::BlowFish;
+use crate::header::{self, Header};
+use crate::journal;
+use crate::{BLOCK_SIZE, DIRECTORY, SKIP_HEADER_SIZE};
+
+/// Re-encrypts every entry block reachable from the root with `blowfish` and
+/// gives the header its checksum, all as one journaled write. File data isn't
+/// encrypted and stays where it is. Returns how many blocks were rewritten.
+pub(crate) fn rekey(archive: &Archive, blowfish: &BlowFish) -> io::Result<usize> {
+    let _writer = archive.writer()?;
+
+    let mut header = Header::from_bytes(&archive.read_bytes(0, SKIP_HEADER_SIZE as u32)?);
+    header.checksum = header::checksum_with(blowfish, &archive.options.checksum_plaintext)?;
+    let mut writes = vec![(0, header.into_bytes())];
+    let mut visited = HashSet::new();
+    collect(archive, archive.root.position, blowfish, &mut visited, &mut writes)?;
+
+    let _guard = archive.write_lock();
+    journal::write(&archive.pk2_path, &writes)?;
+    archive.write_all_bytes(&writes)?;
+    journal::clear(&archive.pk2_path)?;
+    Ok(writes.len() - 1)
+}
+
+fn collect(archive: &Archive, position: u64, blowfish: &BlowFish,
+           visited: &mut HashSet<u64>, writes: &mut Vec<(u64, Vec<u8>)>) -> io::Result<()> {
+    for (block, entries) in archive.get_blocks_of_node(position)? {
+        // a block shared by two directories is rewritten once.
+        if !visited.insert(block) {
+            continue;
+        }
+        // re-encrypted from the raw bytes, whatever the entries hold goes along unchanged.
+        let mut bytes = archive.read_bytes(block, BLOCK_SIZE as u32)?;
+        archive.blowfish.decrypt_in_place(&mut bytes)?;
+        blowfish.encrypt_in_place(&mut bytes)?;
+        writes.push((block, bytes));
+
+        for entry in entries {
+            if entry.entry_type == DIRECTORY && !entry.is_navigation() {
+                collect(archive, entry.position, blowfish, visited, writes)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::archive::{Archive, Mode};
+    use crate::blowfish::BlowFish;
+    use crate::header;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_rekey() {
+        let path = create_empty_archive("rekey");
+        let archive = Archive::open_rw(&path).unwrap();
+        for i in 0..25 {
+            archive.writer().unwrap().add(&format!("textdata/{}.txt", i), format!("file {}", i).as_bytes()).unwrap();
+        }
+        archive.writer().unwrap().add("icon/a.ddj", b"icon").unwrap();
+
+        let archive = archive.rekey(b"169841").unwrap();
+        assert_eq!(archive.extract("textdata/24.txt").unwrap().1, b"file 24");
+        assert_eq!(archive.list("textdata").unwrap().len(), 25);
+        assert_eq!(archive.header().unwrap().checksum, header::checksum(&BlowFish::new(b"169841").unwrap()));
+        assert!(archive.check().unwrap().is_ok());
+        drop(archive);
+
+        assert!(Archive::open(&path).is_err());
+        let archive = Archive::open_with_mode(&path, b"169841", Mode::ReadOnly).unwrap();
+        assert_eq!(archive.extract("icon/a.ddj").unwrap().1, b"icon");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::rc::Rc;
+
+use crate::archive::{Archive, Mode};
+use crate::compact::{check_distinct, ArchiveSlice};
+use crate::options::OpenOptions;
+use crate::progress::Monitor;
+use crate::writer::{self, Node};
+use crate::{join_path, BLOCK_SIZE, DIRECTORY, FILE, EMPTY};
+
+// An entry's name field keeps at least one NUL.
+const MAX_NAME_LENGTH: usize = 80;
+
+/**
+ * What `repair` salvaged, and a line for everything it changed or lost.
+ * The same lines are written to `<dst>.log`.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub files: usize,
+    pub directories: usize,
+    pub log: Vec<String>,
+}
+
+/// Copies everything that can still be reached in `src` into a new,
+/// well formed archive at `dst`: chain cycles are cut, entries that can't
+/// be read or point past the end are dropped and duplicate names are renamed.
+pub fn repair(src: &str, dst: &str) -> io::Result<RepairReport> {
+    repair_with_options(src, dst, &OpenOptions::new())
+}
+
+/// `repair` for an archive of a client with its own key or header, see `OpenOptions`.
+/// `dst` gets the header `options` expect.
+pub fn repair_with_options(src: &str, dst: &str, options: &OpenOptions) -> io::Result<RepairReport> {
+    // a damaged archive is only ever read, a broken header gets replaced anyway.
+    check_distinct(src, dst)?;
+    let archive = options.clone().mode(Mode::ReadOnly).lenient(true).open(src)?;
+    let mut salvager = Salvager {
+        archive: &archive,
+        source: Rc::new(File::open(src)?),
+        file_size: fs::metadata(src)?.len(),
+        visited: HashSet::new(),
+        report: RepairReport::default(),
+    };
+
+    let header = options.header(&archive.blowfish)?.into_bytes();
+    if archive.header()?.into_bytes() != header {
+        salvager.report.log.push("header: rewritten".to_string());
+    }
+
+    if archive.root.entry_type != DIRECTORY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The root entry isn't a directory."));
+    }
+    let children = salvager.salvage(archive.root.position, "")?;
+    let mut root = Node::directory(archive.root, children);
+
+    let mut out = BufWriter::new(fs::OpenOptions::new().write(true).create(true).truncate(true).open(dst)?);
+    writer::write_archive(&mut out, &header, &mut root, &archive.blowfish, &Monitor::default())
+        .and_then(|_| out.into_inner().map_err(|err| err.into_error())?.sync_all())
+        .inspect_err(|_| {
+            let _ = fs::remove_file(dst);
+        })?;
+
+    let report = salvager.report;
+    let mut log: String = report.log.iter().map(|line| format!("{}\n", line)).collect();
+    log.push_str(&format!("salvaged {} files in {} directories\n", report.files, report.directories));
+    fs::write(format!("{}.log", dst), log).inspect_err(|_| {
+        let _ = fs::remove_file(dst);
+    })?;
+    Ok(report)
+}
+
+struct Salvager<'a> {
+    archive: &'a Archive,
+    source: Rc<File>,
+    file_size: u64,
+    visited: HashSet<u64>,
+    report: RepairReport,
+}
+
+impl<'a> Salvager<'a> {
+    fn salvage(&mut self, position: u64, path: &str) -> io::Result<Vec<Node>> {
+        self.report.directories += 1;
+
+        let mut entries = Vec::new();
+        let mut current = position;
+        while current != 0 {
+            if !self.visited.insert(current) {
+                self.log(path, format!("cut the chain at block {}, it was already read", current));
+                break;
+            }
+            if current.saturating_add(BLOCK_SIZE) > self.file_size {
+                self.log(path, format!("dropped block {}, it is past the end of the archive", current));
+                break;
+            }
+            let block = self.archive.get_entries_of_block(current)?;
+            current = block.last().map_or(0, |entry| entry.next_chain);
+            entries.extend(block);
+        }
+
+        let mut names = HashSet::new();
+        let mut children = Vec::new();
+        for mut entry in entries {
+            if entry.entry_type == EMPTY || entry.is_navigation() {
+                continue;
+            }
+            if !entry.is_used() {
+                self.log(path, format!("dropped the entry at {}, its type {} is invalid", entry.offset, entry.entry_type));
+                continue;
+            }
+
+            let raw: Vec<u8> = entry.name.iter().filter(|byte| **byte != 0).copied().collect();
+            let mut name = match String::from_utf8(raw) {
+                Ok(name) => name,
+                Err(err) => {
+                    let name = String::from_utf8_lossy(err.as_bytes()).into_owned();
+                    self.log(path, format!("renamed the entry at {} to {}, its name isn't UTF-8", entry.offset, name));
+                    name
+                },
+            };
+            if name.len() > MAX_NAME_LENGTH {
+                truncate(&mut name, MAX_NAME_LENGTH);
+                self.log(path, format!("cut the name of the entry at {} to {}", entry.offset, name));
+            }
+            if name.is_empty() || name == "." || name == ".." {
+                self.log(path, format!("dropped the entry at {}, it has no usable name", entry.offset));
+                continue;
+            }
+            let unique = unique_name(&name, &names, MAX_NAME_LENGTH);
+            if unique != name {
+                self.log(path, format!("renamed a second {} to {}", name, unique));
+            }
+            names.insert(unique.to_lowercase());
+            entry.set_name(&unique)?;
+
+            let child_path = join_path(path, &unique);
+            if entry.entry_type == FILE {
+                if entry.position.saturating_add(entry.size as u64) > self.file_size {
+                    self.log(&child_path, format!("lost, its {} bytes at {} are past the end of the archive",
+                                                  entry.size, entry.position));
+                    continue;
+                }
+                self.report.files += 1;
+                let data = ArchiveSlice::new(self.source.clone(), entry.position, entry.size as u64);
+                children.push(Node::file(entry, Box::new(data)));
+            } else if self.visited.contains(&entry.position) {
+                self.log(&child_path, format!("dropped, block {} already belongs to another directory", entry.position));
+            } else {
+                let grandchildren = self.salvage(entry.position, &child_path)?;
+                children.push(Node::directory(entry, grandchildren));
+            }
+        }
+        Ok(children)
+    }
+
+    fn log(&mut self, path: &str, message: String) {
+        let path = if path.is_empty() { "." } else { path };
+        self.report.log.push(format!("{}: {}", path, message));
+    }
+}
+
+// "name.txt" becomes "name~1.txt", "name~2.txt", ... until it's free,
+// the stem is shortened to keep it within `max_length` bytes.
+fn unique_name(name: &str, taken: &HashSet<String>, max_length: usize) -> String {
+    if !taken.contains(&name.to_lowercase()) {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= max_length / 2 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    (1..).map(|i| {
+        let suffix = format!("~{}{}", i, extension);
+        let mut stem = stem.to_string();
+        truncate(&mut stem, max_length - suffix.len());
+        stem + &suffix
+    }).find(|candidate| !taken.contains(&candidate.to_lowercase()))
+      .unwrap()
+}
+
+// Cuts `text` to at most `length` bytes without splitting a character.
+fn truncate(text: &mut String, length: usize) {
+    if text.len() > length {
+        let end = (0..=length).rev().find(|end| text.is_char_boundary(*end)).unwrap_or(0);
+        text.truncate(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{repair, repair_with_options};
+    use crate::archive::Archive;
+    use crate::options::OpenOptions;
+    use crate::tests::{create_empty_archive, write_entry};
+
+    #[test]
+    fn test_repair() {
+        let path = create_empty_archive("repair-src");
+        let dst = path.replace("repair-src", "repair-dst");
+        let archive = Archive::open_rw(&path).unwrap();
+        for name in &["a.txt", "b.txt", "c.txt", "d.txt"] {
+            archive.writer().unwrap().add(&format!("dir/{}", name), name.as_bytes()).unwrap();
+        }
+        let entry = |name: &str| archive.extract(&format!("dir/{}", name)).unwrap().0;
+        let (a, mut b, mut c, mut d) = (entry("a.txt"), entry("b.txt"), entry("c.txt"), entry("d.txt"));
+        let block = a.offset - 2 * 128;
+
+        b.set_name("A.TXT").unwrap();
+        c.entry_type = 9;
+        d.size = u32::MAX;
+        let mut last = archive.get_entries_of_block(block).unwrap()[19];
+        last.next_chain = block;
+        for entry in [b, c, d, last] {
+            write_entry(&archive, entry);
+        }
+
+        let report = repair(&path, &dst).unwrap();
+        assert_eq!((report.files, report.directories), (2, 2));
+        assert_eq!(report.log.len(), 4);
+        assert!(fs::read_to_string(format!("{}.log", dst)).unwrap().contains("renamed a second A.TXT to A~1.TXT"));
+
+        let repaired = Archive::open(&dst).unwrap();
+        assert!(repaired.check().unwrap().is_ok());
+        assert_eq!(repaired.extract("dir/a.txt").unwrap().1, b"a.txt");
+        assert_eq!(repaired.extract("dir/A~1.TXT").unwrap().1, b"b.txt");
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.log", dst)).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+
+    #[test]
+    fn test_repair_long_names() {
+        let path = create_empty_archive("repair-names-src");
+        let dst = path.replace("repair-names-src", "repair-names-dst");
+        let long = format!("{}.txt", "a".repeat(76));
+        let archive = Archive::open_rw(&path).unwrap();
+        for name in [long.as_str(), "b.txt", "c.txt", "d.txt"] {
+            archive.writer().unwrap().add(&format!("dir/{}", name), name.as_bytes()).unwrap();
+        }
+        let mut b = archive.extract("dir/b.txt").unwrap().0;
+        b.set_name(&long.to_uppercase()).unwrap();
+        // 81 bytes without a NUL, the last character straddles the cut.
+        let mut c = archive.extract("dir/c.txt").unwrap().0;
+        c.name.copy_from_slice(format!("{}é", "c".repeat(79)).as_bytes());
+        let mut d = archive.extract("dir/d.txt").unwrap().0;
+        d.name[1] = 0xFF;
+        for entry in [b, c, d] {
+            write_entry(&archive, entry);
+        }
+
+        let report = repair(&path, &dst).unwrap();
+        assert_eq!(report.files, 4);
+        assert!(report.log.iter().any(|line| line.contains(&format!("renamed the entry at {} to d\u{FFFD}txt", d.offset))));
+        let repaired = Archive::open(&dst).unwrap();
+        let names: Vec<String> = repaired.list("dir").unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec![long.clone(), format!("{}~1.TXT", "A".repeat(74)), "c".repeat(79), "d\u{FFFD}txt".to_string()]);
+        assert!(repair(&dst, &dst).is_err());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.log", dst)).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+
+    #[test]
+    fn test_repair_with_options() {
+        let path = create_empty_archive("repair-options-src");
+        let dst = path.replace("repair-options-src", "repair-options-dst");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        let archive = archive.rekey(b"169841").unwrap();
+        let mut header = archive.header().unwrap();
+        drop(archive);
+        header.version = 7;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[..256].copy_from_slice(&header.into_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        // the key and the header of the client carry over.
+        let mut options = OpenOptions::new();
+        options.key(b"169841").version(7);
+        let report = repair_with_options(&path, &dst, &options).unwrap();
+        assert!(report.log.is_empty());
+        assert_eq!(options.open(&dst).unwrap().extract("a.txt").unwrap().1, b"a");
+        assert_eq!(fs::read(&dst).unwrap()[..256], fs::read(&path).unwrap()[..256]);
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.log", dst)).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+use crate::archive::Archive;
+use crate::{join_path, Entry, BLOCK_SIZE, DIRECTORY, FILE};
+
+/**
+ * Where the bytes of an archive go, see `Archive::stats`.
+ * Sizes per directory include everything below it, "" is the root.
+ * Extensions are lowercase and without the dot, "" for files without one.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub file_size: u64,
+    pub files: usize,
+    pub directories: usize,
+    pub payload_bytes: u64,
+    pub block_bytes: u64,
+    pub orphaned_bytes: u64,
+    pub free_slots: usize,
+    pub fragmentation: f64,
+    pub by_directory: BTreeMap<String, u64>,
+    pub by_extension: BTreeMap<String, u64>,
+}
+
+pub(crate) fn stats(archive: &Archive) -> io::Result<Stats> {
+    let free_space = archive.scan_free_space()?;
+    let mut stats = Stats {
+        file_size: free_space.file_size(),
+        orphaned_bytes: free_space.free_bytes(),
+        fragmentation: free_space.fragmentation(),
+        ..Stats::default()
+    };
+
+    let mut visited = HashSet::new();
+    walk(archive, &archive.root, "", &mut visited, &mut stats)?;
+    Ok(stats)
+}
+
+// Returns the payload of the directory and everything below it.
+fn walk(archive: &Archive, directory: &Entry, path: &str,
+        visited: &mut HashSet<u64>, stats: &mut Stats) -> io::Result<u64> {
+    if !visited.insert(directory.position) {
+        return Ok(0);
+    }
+
+    let mut total = 0;
+    for (_, entries) in archive.get_blocks_of_node(directory.position)? {
+        stats.block_bytes += BLOCK_SIZE;
+        for entry in entries {
+            if !entry.is_used() {
+                stats.free_slots += 1;
+            } else if entry.is_navigation() {
+                continue;
+            } else if entry.entry_type == DIRECTORY {
+                stats.directories += 1;
+                let child_path = join_path(path, &entry.name());
+                total += walk(archive, &entry, &child_path, visited, stats)?;
+            } else if entry.entry_type == FILE {
+                let name = entry.name();
+                let extension = match name.rfind('.') {
+                    Some(dot) if dot > 0 => name[dot + 1..].to_lowercase(),
+                    _ => String::new(),
+                };
+                stats.files += 1;
+                stats.payload_bytes += entry.size as u64;
+                *stats.by_extension.entry(extension).or_insert(0) += entry.size as u64;
+                total += entry.size as u64;
+            }
+        }
+    }
+
+    stats.by_directory.insert(path.to_string(), total);
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::archive::Archive;
+    use crate::tests::create_empty_archive;
+
+    #[test]
+    fn test_stats() {
+        let path = create_empty_archive("stats");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("textdata/items/sword.txt", &[1; 100]).unwrap();
+        archive.writer().unwrap().add("textdata/shield.TXT", &[2; 50]).unwrap();
+        archive.writer().unwrap().add("icon.ddj", &[3; 20]).unwrap();
+        archive.writer().unwrap().patch("icon.ddj", &[4; 10]).unwrap();
+
+        let stats = archive.stats().unwrap();
+        assert_eq!((stats.files, stats.directories), (3, 2));
+        assert_eq!(stats.payload_bytes, 160);
+        assert_eq!(stats.block_bytes, 3 * 2560);
+        assert_eq!(stats.orphaned_bytes, 20);
+        // 20 slots per block, minus ".", the two top level entries, and
+        // ".", "..", and the children of both directories.
+        assert_eq!(stats.free_slots, 60 - 3 - 4 - 3);
+        assert_eq!(stats.by_directory[""], 160);
+        assert_eq!(stats.by_directory["textdata"], 150);
+        assert_eq!(stats.by_directory["textdata/items"], 100);
+        assert_eq!(stats.by_extension["txt"], 150);
+        assert_eq!(stats.by_extension["ddj"], 10);
+        fs::remove_file(path).unwrap();
+    }
+}
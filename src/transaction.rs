@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+
+use crate::allocator::{Allocator, FreeSpaceMap, Region};
+use crate::archive::{Archive, CachedFreeSpace};
+use crate::history::{self, Version};
+use crate::journal;
+use crate::{split_path, split_new_path, filetime_now, Entry, ENTRY_SIZE, ENTRIES_PER_BLOCK, BLOCK_SIZE, DIRECTORY, FILE};
+
+pub(crate) enum Operation {
+    Add(String, Vec<u8>),
+    Replace(String, Vec<u8>),
+    Delete(String),
+    Rename(String, String),
+    Revert(String, usize),
+}
+
+/**
+ * A batch of changes to an archive. Nothing touches the file until `commit`,
+ * which plans every operation against the archive's free space and then
+ * writes all of the file data followed by all of the changed entries.
+ * If any operation fails to plan, nothing is written at all.
+ */
+pub struct Transaction<'a> {
+    archive: &'a Archive,
+    free_space: &'a mut Option<CachedFreeSpace>,
+    operations: Vec<Operation>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(archive: &'a Archive, free_space: &'a mut Option<CachedFreeSpace>) -> Self {
+        Self { archive, free_space, operations: Vec::new() }
+    }
+
+    /// Adds a new file, creating the directories leading to it if needed.
+    pub fn add(&mut self, path: &str, data: &[u8]) -> &mut Self {
+        self.operations.push(Operation::Add(path.to_string(), data.to_vec()));
+        self
+    }
+
+    /// Points an existing file at new data.
+    pub fn replace(&mut self, path: &str, data: &[u8]) -> &mut Self {
+        self.operations.push(Operation::Replace(path.to_string(), data.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, path: &str) -> &mut Self {
+        self.operations.push(Operation::Delete(path.to_string()));
+        self
+    }
+
+    /// Moves a file or directory, `to` is the full new path.
+    pub fn rename(&mut self, from: &str, to: &str) -> &mut Self {
+        self.operations.push(Operation::Rename(from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Points a file back at one of its old versions, see `Archive::history`.
+    pub fn revert(&mut self, path: &str, version: usize) -> &mut Self {
+        self.operations.push(Operation::Revert(path.to_string(), version));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn commit(&mut self) -> io::Result<()> {
+        apply(self.archive, self.free_space, std::mem::take(&mut self.operations))
+    }
+
+    /// Drops every operation collected so far.
+    pub fn rollback(&mut self) {
+        self.operations.clear();
+    }
+}
+
+/// Plans `operations` against the writer's `free_space` and keeps it up to date,
+/// it's dropped when a write fails half way.
+pub(crate) fn apply(archive: &Archive, free_space: &mut Option<CachedFreeSpace>,
+                    operations: Vec<Operation>) -> io::Result<()> {
+    if operations.is_empty() {
+        return Ok(());
+    }
+
+    let mut staging = Staging::new(archive, archive.free_space_for_commit(free_space)?);
+    for operation in operations {
+        match operation {
+            Operation::Add(path, data) => staging.add(&path, data)?,
+            Operation::Replace(path, data) => staging.replace(&path, data)?,
+            Operation::Delete(path) => staging.delete(&path)?,
+            Operation::Rename(from, to) => staging.rename(&from, &to)?,
+            Operation::Revert(path, version) => staging.revert(&path, version)?,
+        }
+    }
+    *free_space = None;
+    let map = staging.write()?;
+    *free_space = Some(CachedFreeSpace::new(&archive.pk2_path, map)?);
+    Ok(())
+}
+
+/**
+ * The archive as the operations planned so far would leave it.
+ * Blocks are read once and changed in memory, `write` puts them on disk.
+ */
+struct Staging<'a> {
+    archive: &'a Archive,
+    allocator: Allocator,
+    // what the old entries pointed at, free once they are written.
+    freed: Vec<Region>,
+    blocks: HashMap<u64, Vec<Entry>>,
+    dirty: BTreeMap<u64, Entry>,
+    data: Vec<(u64, Vec<u8>)>,
+    versions: Vec<Version>,
+}
+
+impl<'a> Staging<'a> {
+    fn new(archive: &'a Archive, free_space: FreeSpaceMap) -> Self {
+        Self {
+            archive,
+            // space freed by this batch isn't reused by it, the old entries
+            // keep pointing at it until everything is written.
+            allocator: Allocator::new(free_space),
+            freed: Vec::new(),
+            blocks: HashMap::new(),
+            dirty: BTreeMap::new(),
+            data: Vec::new(),
+            versions: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, path: &str, data: Vec<u8>) -> io::Result<()> {
+        let (parent, name) = self.prepare_parent(path)?;
+        if self.find_child(&parent, name)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists.", path)));
+        }
+
+        let mut entry = Entry::new(FILE, name, 0, data.len() as u32)?;
+        entry.position = self.stage_data(data);
+        self.insert_entry(&parent, entry)?;
+        Ok(())
+    }
+
+    fn replace(&mut self, path: &str, data: Vec<u8>) -> io::Result<()> {
+        let size = data.len() as u32;
+        let position = self.stage_data(data);
+        self.point_at(path, position, size)
+    }
+
+    fn revert(&mut self, path: &str, version: usize) -> io::Result<()> {
+        let normalized = history::normalize(path);
+        let old = history::read(&self.archive.pk2_path)?
+            .into_iter()
+            .chain(self.versions.iter().cloned())
+            .filter(|old| old.path == normalized)
+            .nth(version)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                format!("{} has no version {}.", path, version)))?;
+        self.point_at(path, old.position, old.size)
+    }
+
+    // The data the file pointed at so far becomes its latest old version.
+    fn point_at(&mut self, path: &str, position: u64, size: u32) -> io::Result<()> {
+        let mut entry = self.get_entry_of_path(path)?;
+        if entry.entry_type != FILE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a directory, not a file.", path)));
+        }
+
+        // a history keeps the old data in use.
+        if !history::is_enabled(&self.archive.pk2_path) {
+            self.freed.push(Region::new(entry.position, entry.size as u64));
+        }
+        self.versions.push(Version {
+            path: history::normalize(path),
+            position: entry.position,
+            size: entry.size,
+            replaced: filetime_now(),
+        });
+        entry.position = position;
+        entry.size = size;
+        self.update(entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, path: &str) -> io::Result<()> {
+        let entry = self.get_entry_of_path(path)?;
+        if entry.offset == self.archive.root.offset {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't delete the root directory."));
+        }
+        if entry.entry_type == FILE {
+            self.freed.push(Region::new(entry.position, entry.size as u64));
+        } else {
+            let mut visited = HashSet::from([self.archive.root.position]);
+            self.free_tree(entry.position, &mut visited)?;
+        }
+        self.remove_entry(&entry);
+        Ok(())
+    }
+
+    // Every block and all the file data below a deleted directory.
+    fn free_tree(&mut self, position: u64, visited: &mut HashSet<u64>) -> io::Result<()> {
+        if !visited.insert(position) {
+            return Ok(());
+        }
+        for block in self.blocks_of_node(position)? {
+            self.freed.push(Region::new(block, BLOCK_SIZE));
+            for child in self.blocks[&block].clone() {
+                if child.entry_type == FILE {
+                    self.freed.push(Region::new(child.position, child.size as u64));
+                } else if child.entry_type == DIRECTORY && !child.is_navigation() {
+                    self.free_tree(child.position, visited)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let entry = self.get_entry_of_path(from)?;
+        if entry.offset == self.archive.root.offset {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't rename the root directory."));
+        }
+
+        let (parent, name) = self.prepare_parent(to)?;
+        match self.find_child(&parent, name)? {
+            // a case only rename finds the entry itself.
+            Some(existing) if existing.offset != entry.offset => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists.", to)));
+            },
+            _ => {},
+        }
+        if entry.entry_type == DIRECTORY && self.is_inside(&parent, &entry)? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Can't move {} into itself.", from)));
+        }
+
+        let mut moved = entry;
+        moved.set_name(name)?;
+        self.remove_entry(&entry);
+        self.insert_entry(&parent, moved)?;
+
+        if entry.entry_type == DIRECTORY {
+            let up = self.blocks_of_node(entry.position)?.into_iter()
+                         .flat_map(|block| self.blocks[&block].clone())
+                         .find(|child| child.is_navigation() && child.name() == "..");
+            if let Some(mut up) = up {
+                up.position = parent.position;
+                self.update(up);
+            }
+        }
+        Ok(())
+    }
+
+    /// File data only lands in space nothing points at, so it goes first.
+    /// The entries that make it visible go through the journal.
+    /// Old versions are recorded before, a crash in between only
+    /// leaves the current version in the history as well.
+    /// Returns the free space as the archive is now.
+    fn write(self) -> io::Result<FreeSpaceMap> {
+        // a file pointed back at data this batch let go of keeps it.
+        let live: Vec<Region> = self.dirty.values()
+            .filter(|entry| entry.entry_type == FILE)
+            .map(|entry| Region::new(entry.position, entry.size as u64))
+            .collect();
+        let mut free_space = self.allocator.into_map();
+        free_space.release(&self.freed, &live);
+
+        let path = &self.archive.pk2_path;
+        self.archive.write_all_bytes(&self.data)?;
+        history::append(path, &self.versions)?;
+
+        // neighbouring entries go out as a single write.
+        let mut writes = Vec::new();
+        let mut pending: Option<(u64, Vec<u8>)> = None;
+        for (offset, entry) in self.dirty {
+            let mut encrypted = entry.into_bytes();
+            self.archive.blowfish.encrypt_in_place(&mut encrypted)?;
+            match pending.as_mut() {
+                Some((start, buffer)) if *start + buffer.len() as u64 == offset => buffer.extend_from_slice(&encrypted),
+                _ => {
+                    writes.extend(pending.take());
+                    pending = Some((offset, encrypted));
+                },
+            }
+        }
+        writes.extend(pending);
+
+        let _guard = self.archive.write_lock();
+        journal::write(path, &writes)?;
+        self.archive.write_all_bytes(&writes)?;
+        journal::clear(path)?;
+        Ok(free_space)
+    }
+
+    fn stage_data(&mut self, data: Vec<u8>) -> u64 {
+        let position = self.allocator.allocate(data.len() as u64);
+        self.data.push((position, data));
+        position
+    }
+
+    /// The directory `path` goes into, created if it's missing, and the last part of `path`.
+    fn prepare_parent<'p>(&mut self, path: &'p str) -> io::Result<(Entry, &'p str)> {
+        let path_parts = split_new_path(path)?;
+        let (name, directories) = path_parts.split_last().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, "Empty path."))?;
+
+        let mut parent = self.archive.root;
+        for directory in directories {
+            parent = match self.find_child(&parent, directory)? {
+                Some(child) if child.entry_type == DIRECTORY => child,
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("{} is a file, not a directory.", directory))),
+                None => self.create_directory(&parent, directory)?,
+            };
+        }
+        Ok((parent, name))
+    }
+
+    fn get_entry_of_path(&mut self, path: &str) -> io::Result<Entry> {
+        let mut cursor = self.archive.root;
+        for part in split_path(path) {
+            let child = if cursor.entry_type == DIRECTORY { self.find_child(&cursor, part)? } else { None };
+            cursor = child.ok_or_else(||
+                io::Error::new(io::ErrorKind::NotFound, format!("Can't find specified path: {}.", path)))?;
+        }
+        Ok(cursor)
+    }
+
+    fn find_child(&mut self, directory: &Entry, name: &str) -> io::Result<Option<Entry>> {
+        Ok(self.blocks_of_node(directory.position)?
+               .into_iter()
+               .flat_map(|block| self.blocks[&block].clone())
+               .filter(|child| child.is_used() && !child.is_navigation())
+               .find(|child| child.name().eq_ignore_ascii_case(name)))
+    }
+
+    // Whether `directory` is `ancestor` or somewhere below it.
+    fn is_inside(&mut self, directory: &Entry, ancestor: &Entry) -> io::Result<bool> {
+        let mut visited = HashSet::new();
+        let mut cursor = directory.position;
+        while visited.insert(cursor) {
+            if cursor == ancestor.position {
+                return Ok(true);
+            }
+            let up = self.blocks_of_node(cursor)?.into_iter()
+                         .flat_map(|block| self.blocks[&block].clone())
+                         .find(|child| child.is_navigation() && child.name() == "..");
+            match up {
+                Some(up) => cursor = up.position,
+                None => break,
+            }
+        }
+        Ok(false)
+    }
+
+    /// Offsets of a directory's blocks, loading the ones not read yet.
+    fn blocks_of_node(&mut self, position: u64) -> io::Result<Vec<u64>> {
+        let mut blocks = Vec::new();
+        let mut current = position;
+
+        while current != 0 && !blocks.contains(&current) {
+            if !self.blocks.contains_key(&current) {
+                let entries = self.archive.get_entries_of_block(current)?;
+                self.blocks.insert(current, entries);
+            }
+            blocks.push(current);
+            current = self.blocks[&current].last().map_or(0, |entry| entry.next_chain);
+        }
+        Ok(blocks)
+    }
+
+    fn update(&mut self, entry: Entry) {
+        let block = self.blocks.keys()
+                               .find(|block| **block <= entry.offset && entry.offset < **block + BLOCK_SIZE)
+                               .copied()
+                               .expect("Entries are only changed after their block is read.");
+        self.blocks.get_mut(&block).unwrap()[((entry.offset - block) / ENTRY_SIZE) as usize] = entry;
+        self.dirty.insert(entry.offset, entry);
+    }
+
+    // The slot is free from now on, but the chain to the next block must survive.
+    fn remove_entry(&mut self, entry: &Entry) {
+        let mut emptied = Entry::empty();
+        emptied.offset = entry.offset;
+        emptied.next_chain = entry.next_chain;
+        self.update(emptied);
+    }
+
+    /// Puts `entry` in the first empty slot of `directory`,
+    /// chaining a new block to it when all of them are taken.
+    fn insert_entry(&mut self, directory: &Entry, mut entry: Entry) -> io::Result<Entry> {
+        let blocks = self.blocks_of_node(directory.position)?;
+        let last_block = *blocks.last().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+            format!("The directory {} has no entry block, it points at {}.", directory.name(), directory.position)))?;
+
+        let slot = blocks.iter().flat_map(|block| self.blocks[block].iter()).find(|slot| !slot.is_used()).copied();
+        if let Some(slot) = slot {
+            entry.offset = slot.offset;
+            entry.next_chain = slot.next_chain;
+            self.update(entry);
+            return Ok(entry);
+        }
+
+        let block = self.new_block();
+        entry.offset = block;
+        entry.next_chain = 0;
+        self.update(entry);
+
+        let mut last = *self.blocks[&last_block].last().unwrap();
+        last.next_chain = block;
+        self.update(last);
+        Ok(entry)
+    }
+
+    fn create_directory(&mut self, parent: &Entry, name: &str) -> io::Result<Entry> {
+        let block = self.new_block();
+        let mut current = Entry::new(DIRECTORY, ".", block, 0)?;
+        current.offset = block;
+        self.update(current);
+        let mut up = Entry::new(DIRECTORY, "..", parent.position, 0)?;
+        up.offset = block + ENTRY_SIZE;
+        self.update(up);
+
+        self.insert_entry(parent, Entry::new(DIRECTORY, name, block, 0)?)
+    }
+
+    // A block of empty entries, all of them written on commit.
+    fn new_block(&mut self) -> u64 {
+        let block = self.allocator.allocate(BLOCK_SIZE);
+        let entries: Vec<Entry> = (0..ENTRIES_PER_BLOCK).map(|i| {
+            let mut entry = Entry::empty();
+            entry.offset = block + i * ENTRY_SIZE;
+            entry
+        }).collect();
+        for entry in entries.iter() {
+            self.dirty.insert(entry.offset, *entry);
+        }
+        self.blocks.insert(block, entries);
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::archive::Archive;
+    use crate::tests::{create_empty_archive, write_entry};
+
+    fn names(archive: &Archive, directory: &str) -> Vec<String> {
+        archive.list(directory).unwrap().iter().map(|entry| entry.name()).collect()
+    }
+
+    #[test]
+    fn test_commit() {
+        let path = create_empty_archive("transaction");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("textdata/old.txt", b"old").unwrap();
+        archive.writer().unwrap().add("textdata/gone.txt", b"gone").unwrap();
+
+        let mut writer = archive.writer().unwrap();
+        let mut transaction = writer.transaction();
+        transaction.add("textdata/new.txt", b"new")
+                   .replace("textdata/old.txt", b"replaced")
+                   .delete("textdata/gone.txt")
+                   .rename("textdata/new.txt", "moved/renamed.txt");
+        assert_eq!(transaction.len(), 4);
+        // nothing is visible before the commit.
+        assert_eq!(names(&archive, "textdata"), vec!["old.txt", "gone.txt"]);
+        transaction.commit().unwrap();
+
+        assert_eq!(names(&archive, "textdata"), vec!["old.txt"]);
+        assert_eq!(names(&archive, "moved"), vec!["renamed.txt"]);
+        assert_eq!(archive.extract("textdata/old.txt").unwrap().1, b"replaced");
+        assert_eq!(archive.extract("moved/renamed.txt").unwrap().1, b"new");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_commit_writes_nothing() {
+        let path = create_empty_archive("transaction-failed");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let mut writer = archive.writer().unwrap();
+        let mut transaction = writer.transaction();
+        transaction.add("b.txt", b"b").replace("missing.txt", b"c");
+        assert!(transaction.commit().is_err());
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        transaction.add("b.txt", b"b").rollback();
+        transaction.commit().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rename_directory() {
+        let path = create_empty_archive("transaction-rename");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a/b/c.txt", b"c").unwrap();
+        archive.writer().unwrap().add("d/e.txt", b"e").unwrap();
+
+        archive.writer().unwrap().rename("a/b", "d/b").unwrap();
+        assert_eq!(names(&archive, "a"), Vec::<String>::new());
+        assert_eq!(names(&archive, "d"), vec!["e.txt", "b"]);
+        assert_eq!(archive.extract("d/b/c.txt").unwrap().1, b"c");
+        assert!(archive.writer().unwrap().rename("d", "d/b/d").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dangling_directory() {
+        let path = create_empty_archive("transaction-dangling");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("d/a.txt", b"a").unwrap();
+        let mut directory = archive.list(".").unwrap()[0];
+        directory.position = 0;
+        write_entry(&archive, directory);
+
+        let err = archive.writer().unwrap().add("d/b.txt", b"b").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_parent_parts() {
+        let path = create_empty_archive("transaction-parent");
+        let archive = Archive::open_rw(&path).unwrap();
+        archive.writer().unwrap().add("a.txt", b"a").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        for bad in ["../x.txt", "data/../x.txt", "data\\x.txt", ".."] {
+            let err = archive.writer().unwrap().add(bad, b"x").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(archive.writer().unwrap().rename("a.txt", bad).is_err());
+        }
+        assert_eq!(fs::read(&path).unwrap(), before);
+        // "." parts are the directory itself and just get dropped.
+        archive.writer().unwrap().add("./data/./x.txt", b"x").unwrap();
+        assert_eq!(names(&archive, "data"), vec!["x.txt"]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{self, Read, Write, Seek, SeekFrom};
+
+use crate::blowfish::BlowFish;
+use crate::progress::Monitor;
+use crate::{join_path, Entry, DIRECTORY, ENTRIES_PER_BLOCK, BLOCK_SIZE, SKIP_HEADER_SIZE};
+
+/**
+ * A file or directory to be written into a fresh archive.
+ * `entry` carries the name, type and dates, its position, size
+ * and chaining are decided by `write_archive`.
+ */
+pub(crate) struct Node {
+    pub entry: Entry,
+    pub children: Vec<Node>,
+    pub data: Option<Box<dyn Read>>,
+}
+
+impl Node {
+    pub fn directory(entry: Entry, children: Vec<Node>) -> Self {
+        Self { entry, children, data: None }
+    }
+
+    pub fn file(entry: Entry, data: Box<dyn Read>) -> Self {
+        Self { entry, children: Vec::new(), data: Some(data) }
+    }
+
+    // The root only has ".", every other directory also has "..".
+    fn blocks_needed(&self, is_root: bool) -> u64 {
+        let slots = self.children.len() as u64 + if is_root { 1 } else { 2 };
+        slots.div_ceil(ENTRIES_PER_BLOCK)
+    }
+
+    fn total_blocks(&self, is_root: bool) -> u64 {
+        self.blocks_needed(is_root) + self.children.iter()
+                                          .filter(|child| child.entry.entry_type == DIRECTORY)
+                                          .map(|child| child.total_blocks(false))
+                                          .sum::<u64>()
+    }
+}
+
+/**
+ * Lays out a whole archive with no holes: the header, then every entry block
+ * (a directory's blocks are always next to each other), then the file data.
+ * `root` is the root "." entry with the top level entries as its children.
+ * The output only depends on the tree, children are written in the order given.
+ * Every file written is reported to `monitor`.
+ */
+pub(crate) fn write_archive<W: Write + Seek>(out: &mut W, header: &[u8], root: &mut Node,
+                                             blowfish: &BlowFish, monitor: &Monitor) -> io::Result<u64> {
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(header)?;
+
+    let mut writer = Writer {
+        out,
+        blowfish,
+        monitor,
+        next_block: SKIP_HEADER_SIZE,
+        next_data: SKIP_HEADER_SIZE + root.total_blocks(true) * BLOCK_SIZE,
+    };
+    let blocks = writer.reserve_blocks(root, true);
+    writer.write_directory(root, &blocks, None, "")?;
+    Ok(writer.next_data)
+}
+
+// "." and ".." get the dates of the directory they are in.
+fn navigation(name: &str, position: u64, directory: &Entry) -> io::Result<Entry> {
+    let mut entry = Entry::new(DIRECTORY, name, position, 0)?;
+    entry.access_date = directory.access_date;
+    entry.create_date = directory.create_date;
+    entry.modify_date = directory.modify_date;
+    Ok(entry)
+}
+
+struct Writer<'a, W> {
+    out: &'a mut W,
+    blowfish: &'a BlowFish,
+    monitor: &'a Monitor,
+    next_block: u64,
+    next_data: u64,
+}
+
+impl<W: Write + Seek> Writer<'_, W> {
+    fn reserve_blocks(&mut self, node: &Node, is_root: bool) -> Vec<u64> {
+        (0..node.blocks_needed(is_root)).map(|_| {
+            let block = self.next_block;
+            self.next_block += BLOCK_SIZE;
+            block
+        }).collect()
+    }
+
+    fn write_directory(&mut self, node: &mut Node, blocks: &[u64], parent: Option<u64>, path: &str) -> io::Result<()> {
+        let mut entries = Vec::with_capacity(node.children.len() + 2);
+
+        entries.push(navigation(".", blocks[0], &node.entry)?);
+        if let Some(parent) = parent {
+            entries.push(navigation("..", parent, &node.entry)?);
+        }
+
+        for child in node.children.iter_mut() {
+            let mut entry = child.entry;
+            let child_path = join_path(path, &entry.name());
+            if entry.entry_type == DIRECTORY {
+                let child_blocks = self.reserve_blocks(child, false);
+                entry.position = child_blocks[0];
+                entry.size = 0;
+                self.write_directory(child, &child_blocks, Some(blocks[0]), &child_path)?;
+            } else {
+                self.monitor.check()?;
+                entry.position = self.next_data;
+                entry.size = self.write_data(child)?;
+                self.monitor.advance(entry.size as u64, &child_path)?;
+            }
+            entries.push(entry);
+        }
+
+        entries.resize(blocks.len() * ENTRIES_PER_BLOCK as usize, Entry::empty());
+        for (i, (block, chunk)) in blocks.iter().zip(entries.chunks_mut(ENTRIES_PER_BLOCK as usize)).enumerate() {
+            let mut buffer = Vec::with_capacity(BLOCK_SIZE as usize);
+            for (j, entry) in chunk.iter_mut().enumerate() {
+                // whatever was read into the padding would make two
+                // writes of the same tree differ.
+                entry.padding = 0;
+                entry.next_chain = 0;
+                if j + 1 == ENTRIES_PER_BLOCK as usize {
+                    entry.next_chain = blocks.get(i + 1).copied().unwrap_or(0);
+                }
+                buffer.extend_from_slice(&entry.into_bytes());
+            }
+            self.blowfish.encrypt_in_place(&mut buffer)?;
+            self.out.seek(SeekFrom::Start(*block))?;
+            self.out.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, node: &mut Node) -> io::Result<u32> {
+        let mut data = match node.data.take() {
+            Some(data) => data,
+            None => return Ok(0),
+        };
+        self.out.seek(SeekFrom::Start(self.next_data))?;
+        let size = io::copy(&mut data, self.out)?;
+        if size > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} is too big for an archive entry.", node.entry.name())));
+        }
+        self.next_data += size;
+        Ok(size as u32)
+    }
+}